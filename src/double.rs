@@ -0,0 +1,149 @@
+//! Experimental double-hashed databases: two complete GCS tables built from
+//! independent 64-bit slices of each key's digest, where a key is only
+//! reported present if both tables contain their half.
+//!
+//! The container is simply the two tables back to back, followed by a
+//! trailer giving their lengths:
+//!
+//! ```text
+//! [table A][table B][A length: u64 BE][B length: u64 BE][GCSDv0]
+//! ```
+//!
+//! Each table is an ordinary database in its own right.
+//!
+//! At 1-in-p1 and 1-in-p2 the tables miss independently, so the combined
+//! false-positive rate is 1/(p1*p2).  Each table spends about log2(p) + 1.6
+//! bits an item, so the pair costs some 1.6 bits an item more than a single
+//! table at 1-in-(p1*p2).  That makes double hashing worthwhile only when
+//! a single table can't be built at all: n*p must fit in 64 bits, which caps
+//! a single table at about 1-in-(2^64/n).
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use gcs::GCSReader;
+
+const DOUBLE_MAGIC: &[u8; 8] = b"[GCSDv0]";
+const DOUBLE_TRAILER_LEN: u64 = 24;
+
+/// A view of `len` bytes of `inner` starting at `start`, so a reader can
+/// treat one table of the container as a whole file.
+pub struct Window<R> {
+    inner: R,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<R: Read + Seek> Window<R> {
+    pub fn new(mut inner: R, start: u64, len: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            inner,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read> Read for Window<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let max = ::std::cmp::min(buf.len() as u64, remaining) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for Window<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => offset(self.len, off),
+            SeekFrom::Current(off) => offset(self.pos, off),
+        };
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the table",
+            )
+        })?;
+
+        self.inner.seek(SeekFrom::Start(self.start + target))?;
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+/// `base` moved by a seek offset, or None if that's before the start.
+pub fn offset(base: u64, off: i64) -> Option<u64> {
+    if off < 0 {
+        base.checked_sub(off.wrapping_neg() as u64)
+    } else {
+        base.checked_add(off as u64)
+    }
+}
+
+pub type TableReader = GCSReader<BufReader<Window<File>>>;
+
+pub struct DoubleReader {
+    pub first: TableReader,
+    pub second: TableReader,
+}
+
+impl DoubleReader {
+    /// Open `filename` if it's a double-hashed container, or return None if
+    /// it isn't one.
+    pub fn open<P: AsRef<Path>>(filename: P) -> io::Result<Option<Self>> {
+        let mut file = File::open(&filename)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        if size < DOUBLE_TRAILER_LEN {
+            return Ok(None);
+        }
+
+        file.seek(SeekFrom::End(-(DOUBLE_TRAILER_LEN as i64)))?;
+        let first_len = file.read_u64::<BigEndian>()?;
+        let second_len = file.read_u64::<BigEndian>()?;
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+
+        if magic != *DOUBLE_MAGIC {
+            return Ok(None);
+        }
+        if first_len.checked_add(second_len) != Some(size - DOUBLE_TRAILER_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Corrupt double-hashed container",
+            ));
+        }
+
+        let first = open_table(file, 0, first_len)?;
+        let second = open_table(File::open(&filename)?, first_len, second_len)?;
+        Ok(Some(Self { first, second }))
+    }
+
+    pub fn exists(&mut self, first: u64, second: u64) -> io::Result<bool> {
+        Ok(self.first.exists(first)? && self.second.exists(second)?)
+    }
+}
+
+fn open_table(file: File, start: u64, len: u64) -> io::Result<TableReader> {
+    let mut table = GCSReader::new(BufReader::new(Window::new(file, start, len)?));
+    table.initialize()?;
+    Ok(table)
+}
+
+/// Append the trailer that turns two tables, already written back to back,
+/// into a container.
+pub fn write_trailer<W: Write>(io: &mut W, first_len: u64, second_len: u64) -> io::Result<()> {
+    io.write_u64::<BigEndian>(first_len)?;
+    io.write_u64::<BigEndian>(second_len)?;
+    io.write_all(DOUBLE_MAGIC)
+}
@@ -1,19 +1,359 @@
+use std::error;
+use std::fmt;
 use std::io;
 use std::io::SeekFrom;
 use std::io::{Error, ErrorKind};
 use std::iter;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rayon::prelude::*;
 
 use bitrw::{BitReader, BitWriter};
 use status::Status;
+use Endian;
 
+// The sixth byte records the byte order of the footer, index and header
+// integers: 'v' for big-endian, as it always was, or 'l' for little-endian.
 const GCS_MAGIC: &[u8; 8] = b"[GCS:v0]";
+const GCS_MAGIC_PREFIX: &[u8; 5] = b"[GCS:";
+const GCS_VERSION: u32 = 0;
+
+// Optional leading header: magic, N, P.  Its sixth byte gives the byte order
+// like the footer's, so a reader that can't seek can start from the header.
+// Files written before that always say 'v', so the footer still decides
+// when it can be read.
+// 3*8=24 bytes
+const GCS_HEADER_MAGIC: &[u8; 8] = b"[GCSHv0]";
+pub const GCS_HEADER_LEN: u64 = 24;
+
+// Optional payloads sit between the index and the footer, where older readers
+// ignore them: one fixed-width value per member in order, then the width in
+// bytes, the index granularity (needed to work out a member's rank) and
+// magic.  3*8=24 bytes after the payloads themselves.
+const GCS_PAYLOAD_MAGIC: &[u8; 8] = b"[GCSPv0]";
+const GCS_PAYLOAD_TRAILER_LEN: u64 = 24;
+
+// Shards written by split end with a trailer just before the footer: the
+// value their first member is encoded from, so they needn't start with a
+// long run up from 0, then their member count and magic.  Older readers
+// ignore it and decode from 0, so they can't read shards.  3*8=24 bytes.
+const GCS_SHARD_MAGIC: &[u8; 8] = b"[GCSSv0]";
+const GCS_SHARD_TRAILER_LEN: u64 = 24;
+
+// Databases whose keys were hashed with little-endian digest truncation end
+// with a trailer after any others saying so, so queries can follow suit: 1
+// for little-endian, then magic.  Big-endian ones, as every database was
+// before, don't carry it, which keeps them byte for byte unchanged.  2*8=16
+// bytes.
+const GCS_DIGEST_MAGIC: &[u8; 8] = b"[GCSDv0]";
+const GCS_DIGEST_TRAILER_LEN: u64 = 16;
+
+/// Why a file's footer was rejected, carried inside the `io::Error` returned
+/// by `GCSReader::initialize` so callers can tell the two apart.
+#[derive(Debug)]
+pub enum FormatError {
+    /// The footer doesn't carry a GCS magic at all.
+    BadMagic,
+    /// A GCS file, but from a newer version of the format.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::BadMagic => write!(f, "Not a GCS file"),
+            FormatError::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported GCS format version {} (this build reads up to {})",
+                v, GCS_VERSION
+            ),
+        }
+    }
+}
+
+impl error::Error for FormatError {}
+
+impl From<FormatError> for Error {
+    fn from(e: FormatError) -> Self {
+        Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+/// A read of some part of a database that failed, with which part and its
+/// byte offset, so truncated or corrupt files can be tracked down.  Carried
+/// inside an `io::Error` of the same kind as the failure.
+#[derive(Debug)]
+pub struct GcsError {
+    what: String,
+    offset: u64,
+    cause: Error,
+}
+
+impl fmt::Display for GcsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} reading {} at offset {:#x}",
+            self.cause, self.what, self.offset
+        )
+    }
+}
+
+impl error::Error for GcsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+/// Wrap `cause` in a `GcsError` saying it happened reading `what` at byte
+/// `offset`.
+fn context<S: Into<String>>(cause: Error, what: S, offset: u64) -> Error {
+    Error::new(
+        cause.kind(),
+        GcsError {
+            what: what.into(),
+            offset,
+            cause,
+        },
+    )
+}
+
+/// Read two integers in `order`, as the header, index entries and payload
+/// trailer store them.
+fn read_pair<R: io::Read>(order: Endian, io: &mut R) -> io::Result<(u64, u64)> {
+    Ok((order.read_from(io)?, order.read_from(io)?))
+}
+
+fn magic_for(order: Endian) -> [u8; 8] {
+    let mut magic = *GCS_MAGIC;
+    if let Endian::Little = order {
+        magic[5] = b'l';
+    }
+    magic
+}
+
+fn header_magic_for(order: Endian) -> [u8; 8] {
+    let mut magic = *GCS_HEADER_MAGIC;
+    if let Endian::Little = order {
+        magic[5] = b'l';
+    }
+    magic
+}
+
+/// The byte order of a `[GCSHv0]` or `[GCSHl0]` header magic, if it is one.
+fn parse_header_magic(magic: &[u8; 8]) -> Option<Endian> {
+    if *magic == header_magic_for(Endian::Big) {
+        Some(Endian::Big)
+    } else if *magic == header_magic_for(Endian::Little) {
+        Some(Endian::Little)
+    } else {
+        None
+    }
+}
+
+/// Extract the byte order and N from a `[GCS:vN]` or `[GCS:lN]` magic, where
+/// N is one or two decimal digits.
+fn parse_magic(magic: &[u8; 8]) -> Option<(Endian, u32)> {
+    if magic[..5] != GCS_MAGIC_PREFIX[..] {
+        return None;
+    }
+
+    let order = match magic[5] {
+        b'v' => Endian::Big,
+        b'l' => Endian::Little,
+        _ => return None,
+    };
+
+    let digits = if magic[7] == b']' {
+        &magic[6..7]
+    } else {
+        &magic[6..8]
+    };
+
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    Some((
+        order,
+        digits.iter().fold(0, |v, d| v * 10 + u32::from(d - b'0')),
+    ))
+}
+
+/// Projected sizes in bytes of a database, from `estimate_gcs_size`.  The
+/// optional header adds another `GCS_HEADER_LEN`.
+#[derive(Debug, Clone, Copy)]
+pub struct GcsSizeEstimate {
+    pub data: u64,
+    pub index: u64,
+    pub footer: u64,
+    /// Peak memory while building: every hash at once, plus the index.
+    pub memory: u64,
+}
+
+impl GcsSizeEstimate {
+    pub fn total(&self) -> u64 {
+        self.data + self.index + self.footer
+    }
+}
+
+/// Estimate the size of a database of `n` distinct items at 1-in-`p` without
+/// building it.  Each item costs log2(p) bits of remainder plus roughly
+/// 1/(1-1/e) bits of unary quotient.
+pub fn estimate_gcs_size(n: u64, p: u64, index_granularity: u64) -> GcsSizeEstimate {
+    let data_bits = (n as f64) * item_bits(p);
+    let index = n.checked_div(index_granularity).unwrap_or(0) * 16;
+
+    GcsSizeEstimate {
+        data: (data_bits / 8.0).ceil() as u64,
+        index,
+        footer: 40,
+        memory: n * 8 + index,
+    }
+}
+
+/// Expected bits of data per item at 1-in-`p`.
+fn item_bits(p: u64) -> f64 {
+    let quotient_bits = 1.0 / (1.0 - (-1.0f64).exp());
+    (p as f64).log2() + quotient_bits
+}
+
+/// The fewest bits per item any encoding of a 1-in-`p` set can average,
+/// about log2(p) + 1.44.  Rice coding comes within about 0.14 bits of it.
+pub fn min_item_bits(p: u64) -> f64 {
+    (p as f64).log2() + ::std::f64::consts::LOG2_E
+}
+
+/// Bits of data per item achieved against `min_item_bits`, as printed by
+/// create and info.
+pub fn item_bits_summary(data_bytes: u64, items: u64, p: u64) -> String {
+    let achieved = (data_bytes * 8) as f64 / items as f64;
+    let min = min_item_bits(p);
+    format!(
+        "Bits per item: {:.3}, theoretical minimum {:.3} ({:.1}% efficient)",
+        achieved,
+        min,
+        (min / achieved) * 100.0
+    )
+}
+
+/// The index granularity that places index points about every `bits` bits,
+/// for estimating the size of a database indexed by bit position.
+pub fn granularity_for_bits(p: u64, bits: u64) -> u64 {
+    ::std::cmp::max(1, (bits as f64 / item_bits(p)) as u64)
+}
+
+/// What `recommend_index_granularity` should aim for.
+#[derive(Debug, Clone, Copy)]
+pub enum IndexTarget {
+    /// Mean bits of data decoded per query.
+    QueryBits(u64),
+    /// Size of the index as a fraction of the data.
+    IndexFraction(f64),
+}
+
+/// Recommend an index granularity for `n` items at 1-in-`p` that meets
+/// `target`.  Queries decode half the gap between index points on average,
+/// and each index point costs 128 bits.  Zero means no index is worthwhile.
+pub fn recommend_index_granularity(n: u64, p: u64, target: IndexTarget) -> u64 {
+    let g = match target {
+        IndexTarget::QueryBits(bits) => 2.0 * bits as f64 / item_bits(p),
+        IndexTarget::IndexFraction(fraction) => 128.0 / (item_bits(p) * fraction),
+    };
+
+    if g >= n as f64 {
+        0
+    } else {
+        ::std::cmp::max(1, g.round() as u64)
+    }
+}
+
+/// Pick the largest false-positive rate whose database is expected to fit in
+/// `max_size` bytes.  Rice coding spends ceil(log2(p)) bits on every remainder,
+/// so only powers of two are worth considering.
+pub fn p_for_size(n: u64, index_granularity: u64, header: bool, max_size: u64) -> Option<u64> {
+    let budget = if header {
+        max_size.saturating_sub(GCS_HEADER_LEN)
+    } else {
+        max_size
+    };
+
+    (1..64)
+        .rev()
+        .map(|log2p| 1u64 << log2p)
+        .filter(|p| n.checked_mul(*p).is_some())
+        .find(|p| estimate_gcs_size(n, *p, index_granularity).total() <= budget)
+}
+
+// Below this many index entries a plain binary search is just as quick.
+const INTERPOLATION_MIN_LEN: usize = 64;
+
+const QUOTIENT_BUCKETS: usize = 16;
+
+// A leading gap with a quotient this long gets an index point of its own, so
+// queries needn't decode the whole run.
+const LONG_LEADING_RUN: u64 = 64;
+
+/// Distribution of Golomb quotients seen while encoding.  A well-matched p
+/// keeps almost all of them at 0 or 1.
+#[derive(Default)]
+struct QuotientStats {
+    buckets: [u64; QUOTIENT_BUCKETS + 1],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl QuotientStats {
+    fn add(&mut self, q: u64) {
+        self.buckets[::std::cmp::min(q, QUOTIENT_BUCKETS as u64) as usize] += 1;
+        self.count += 1;
+        self.sum += q;
+        self.max = ::std::cmp::max(self.max, q);
+    }
+
+    fn print(&self, status: &mut Status) {
+        status.message("Quotient histogram:");
+        let last = ::std::cmp::min(self.max, QUOTIENT_BUCKETS as u64) as usize;
+        for (q, &count) in self.buckets.iter().enumerate().take(last + 1) {
+            status.message(&format!(
+                "{:>5}{}: {} ({:.2}%)",
+                q,
+                if q == QUOTIENT_BUCKETS { "+" } else { " " },
+                count,
+                (count as f64 / self.count as f64) * 100.0
+            ));
+        }
+        status.message(&format!(
+            "Mean quotient: {:.3}, max: {}",
+            self.sum as f64 / self.count as f64,
+            self.max
+        ));
+    }
+}
+
+/// The largest payload that fits in `width` bytes.
+pub fn payload_mask(width: u8) -> u64 {
+    if width >= 8 {
+        u64::MAX
+    } else {
+        (1 << (u32::from(width) * 8)) - 1
+    }
+}
+
+/// Bits needed for a remainder in 0..p, ceil(log2(p)).  Worked out on the
+/// integer, since going through f64 rounds p close to 2^64 down a bit.
+fn remainder_bits(p: u64) -> u8 {
+    if p <= 1 {
+        0
+    } else {
+        (64 - (p - 1).leading_zeros()) as u8
+    }
+}
 
 pub struct GolombEncoder<W> {
     p: u64,
     log2p: u8,
+    pow2: bool,
     inner: BitWriter<W>,
 }
 
@@ -21,19 +361,34 @@ impl<W: io::Write> GolombEncoder<W> {
     pub fn new(inner: W, p: u64) -> Self {
         Self {
             p,
-            log2p: (p as f64).log2().ceil().trunc() as u8,
+            log2p: remainder_bits(p),
+            pow2: p.is_power_of_two(),
             inner: BitWriter::<W>::new(inner),
         }
     }
 
     pub fn encode(&mut self, val: u64) -> io::Result<usize> {
-        let q: u64 = val / self.p;
-        let r: u64 = val % self.p;
+        // With a power of two p the divmod is just a shift and a mask.
+        let (q, r) = if self.pow2 {
+            (val >> self.log2p, val & (self.p - 1))
+        } else {
+            (val / self.p, val % self.p)
+        };
 
         let mut written = 0;
 
+        // Unary quotient: q ones and a zero.  Long runs, such as the gap
+        // before the first member of a shard, go out in pieces.
+        let mut q = q;
+        while q >= 32 {
+            written += self.inner.write_bits(32, 0xffff_ffff)?;
+            q -= 32;
+        }
         written += self.inner.write_bits((q + 1) as u8, (1 << (q + 1)) - 2)?;
-        written += self.inner.write_bits(self.log2p, r)?;
+        // With p of 1 there's no remainder, so don't ask for a 0-bit write.
+        if self.log2p > 0 {
+            written += self.inner.write_bits(self.log2p, r)?;
+        }
 
         Ok(written)
     }
@@ -47,39 +402,243 @@ impl<W: io::Write> GolombEncoder<W> {
     }
 }
 
+/// Builds a database in two phases: configure and `add` values, then
+/// `finish`, which consumes the builder.  Values can't be added after
+/// `finish`, nor can it run twice, because there's no builder left to call
+/// them on; the writer it hands back is all that remains.
 pub struct GCSBuilder<T: io::Write> {
     io: T,
     n: u64,
     p: u64,
     index_granularity: usize,
+    header: bool,
+    max_size: Option<u64>,
+    stats: bool,
+    order: Endian,
+    digest_order: Endian,
     values: Vec<u64>,
+    /// (hash, payload) pairs, used instead of `values` once a payload width
+    /// is set.
+    payloads: Option<Vec<(u64, u64)>>,
+    payload_width: u8,
+    modulus_n: Option<u64>,
+    /// Encode the first value from this rather than 0, for shards.
+    shard_base: Option<u64>,
+    index_bits: Option<u64>,
+    /// Choose the index granularity to meet this once the count is known.
+    index_target: Option<IndexTarget>,
+    /// Whether any value was added with `add`, rather than `add_reduced`,
+    /// and so needs reducing mod n*p.
+    unreduced: bool,
 }
 
 impl<T: io::Write> GCSBuilder<T> {
-    pub fn new(
-        io: T,
-        n: u64,
-        p: u64,
-        index_granularity: u64,
-    ) -> Result<GCSBuilder<T>, &'static str> {
+    pub fn new(io: T, n: u64, p: u64, index_granularity: u64) -> io::Result<GCSBuilder<T>> {
+        if p == 0 {
+            return Err(Error::new(ErrorKind::Other, "p must be at least 1"));
+        }
+
         match n.checked_mul(p) {
             Some(_) => Ok(GCSBuilder {
                 io,
                 n,
                 p,
                 index_granularity: index_granularity as usize,
+                header: false,
+                max_size: None,
+                stats: false,
+                order: Endian::Big,
+                digest_order: Endian::Big,
                 values: Vec::with_capacity(n as usize),
+                payloads: None,
+                payload_width: 0,
+                modulus_n: None,
+                shard_base: None,
+                index_bits: None,
+                index_target: None,
+                unreduced: false,
             }),
-            None => Err("n*p must fit in u64"),
+            None => Err(Error::new(ErrorKind::Other, "n*p must fit in u64")),
         }
     }
 
+    /// Choose the index granularity in `finish` to meet `target`, from the
+    /// number of distinct values actually added rather than an estimate.
+    pub fn set_index_target(&mut self, target: IndexTarget) {
+        self.index_target = Some(target);
+    }
+
+    /// Place an index point every `bits` bits of encoded data, rather than
+    /// every index-granularity values, so the index covers the file evenly
+    /// however the values cluster.  Readers need no changes, but payloads
+    /// can't find their rank without a fixed granularity.
+    pub fn set_index_bits(&mut self, bits: u64) {
+        self.index_bits = if bits > 0 { Some(bits) } else { None };
+    }
+
+    /// Also write N and P in a header at the start of the file, so a reader
+    /// can learn them without seeking to the footer.
+    pub fn set_header(&mut self, header: bool) {
+        self.header = header;
+    }
+
+    /// Ignore the false-positive rate given to `new` and instead choose the
+    /// largest one that keeps the output within `max_size` bytes.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = Some(max_size);
+    }
+
+    /// Print a histogram of Golomb quotients after encoding.
+    pub fn set_stats(&mut self, stats: bool) {
+        self.stats = stats;
+    }
+
+    /// Byte order of the integers in the header, index and footer.  Readers
+    /// pick it up from the magic, so this only matters to other decoders.
+    pub fn set_byte_order(&mut self, order: Endian) {
+        self.order = order;
+    }
+
+    /// Byte order the keys' digests were truncated in to make the values
+    /// added, recorded so queries can hash the same way.
+    pub fn set_digest_order(&mut self, order: Endian) {
+        self.digest_order = order;
+    }
+
+    /// Store a `width`-byte payload alongside each member, returned by
+    /// `GCSReader::lookup_bounded`.  Must be set before anything is added.
+    pub fn set_payload_width(&mut self, width: u8) -> io::Result<()> {
+        if width == 0 || width > 8 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "payload width must be 1 to 8 bytes",
+            ));
+        }
+        if !self.values.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "payload width must be set before adding values",
+            ));
+        }
+
+        let capacity = self.values.capacity();
+        self.values = Vec::new();
+        self.payloads = Some(Vec::with_capacity(capacity));
+        self.payload_width = width;
+        Ok(())
+    }
+
     pub fn add(&mut self, value: u64) {
-        self.values.push(value);
+        self.unreduced = true;
+        match self.payloads {
+            Some(ref mut payloads) => payloads.push((value, 0)),
+            None => self.values.push(value),
+        }
+    }
+
+    /// Record `n` rather than the number of distinct values added, so values
+    /// are reduced mod `n*p` of some larger set.  For shards of an existing
+    /// database, whose values are already reduced and must stay as they are.
+    pub fn set_modulus_n(&mut self, n: u64) {
+        self.modulus_n = Some(n);
     }
 
-    pub fn finish(mut self, status: &mut Status) -> io::Result<()> {
-        self.n = self.values.len() as u64;
+    /// Encode the first value as a delta from `base` rather than 0, for a
+    /// shard holding only values from `base` up.  It's written to a trailer
+    /// for readers to start from, with the shard's member count.
+    pub fn set_shard_base(&mut self, base: u64) {
+        self.shard_base = Some(base);
+    }
+
+    /// Add a value the caller guarantees is already reduced mod n*p, such as
+    /// a member of another database with the same modulus.  If every value
+    /// comes this way, `finish` skips reducing and resorting them.
+    pub fn add_reduced(&mut self, value: u64) {
+        debug_assert!(self.modulus_n.map_or(true, |n| n
+            .checked_mul(self.p)
+            .map_or(true, |np| value < np)));
+        match self.payloads {
+            Some(ref mut payloads) => payloads.push((value, 0)),
+            None => self.values.push(value),
+        }
+    }
+
+    /// Bytes taken by the payloads of `n` members and their trailer.
+    fn payload_size(&self, n: u64) -> u64 {
+        if self.payload_width > 0 {
+            n * u64::from(self.payload_width) + GCS_PAYLOAD_TRAILER_LEN
+        } else {
+            0
+        }
+    }
+
+    /// Add a member with a payload, of which only the low payload-width
+    /// bytes are kept.  Where members are duplicated or collide, one of their
+    /// payloads wins.
+    pub fn add_with_payload(&mut self, value: u64, payload: u64) {
+        self.unreduced = true;
+        let payload = payload & payload_mask(self.payload_width);
+        match self.payloads {
+            Some(ref mut payloads) => payloads.push((value, payload)),
+            None => self.values.push(value),
+        }
+    }
+
+    /// Encode the set and write it out, returning the writer so in-memory
+    /// builds (e.g. into a `Vec<u8>`) can recover their buffer.  This ends
+    /// the builder; to build again, start a new one on the returned writer.
+    pub fn finish(mut self, status: &mut Status) -> io::Result<T> {
+        if self.index_bits.is_some() && self.payloads.is_some() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "payloads need an index by count, not by bits",
+            ));
+        }
+
+        // The modulus depends on the real number of distinct items, so
+        // deduplicate the raw hashes before settling on n and p.
+        status.stage("Sort");
+        match self.payloads {
+            Some(ref mut payloads) => payloads.par_sort_unstable_by_key(|&(v, _)| v),
+            None => self.values.par_sort_unstable(),
+        }
+
+        status.stage("Deduplicate");
+        self.n = match self.payloads {
+            Some(ref mut payloads) => {
+                payloads.dedup_by_key(|&mut (v, _)| v);
+                payloads.len() as u64
+            }
+            None => {
+                self.values.dedup();
+                self.values.len() as u64
+            }
+        };
+        status.finish_stage();
+
+        if let Some(n) = self.modulus_n {
+            self.n = n;
+        }
+
+        if let Some(max_size) = self.max_size {
+            let gran = self.index_granularity as u64;
+            let max_size = max_size.saturating_sub(self.payload_size(self.n));
+            self.p = match p_for_size(self.n, gran, self.header, max_size) {
+                Some(p) => p,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        "Database cannot fit in the requested size",
+                    ));
+                }
+            };
+
+            status.message(&format!(
+                "Using a 1 in {} false-positive rate to fit {} items in {} bytes",
+                self.p, self.n, max_size
+            ));
+        }
+
         let np = match self.n.checked_mul(self.p) {
             Some(np) => np,
             None => {
@@ -88,31 +647,127 @@ impl<T: io::Write> GCSBuilder<T> {
         };
 
         status.stage("Normalise");
-        self.values.par_iter_mut().for_each(|v| *v %= np);
+        let payloads = match self.payloads.take() {
+            Some(mut payloads) => {
+                payloads.par_iter_mut().for_each(|e| e.0 %= np);
 
-        status.stage("Sort");
-        self.values.par_sort_unstable();
+                status.stage("Resort");
+                payloads.par_sort_unstable_by_key(|&(v, _)| v);
 
-        status.stage("Deduplicate");
-        self.values.dedup();
+                status.stage("Deduplicate collisions");
+                payloads.dedup_by_key(|&mut (v, _)| v);
 
-        let index_points = self.values.len() / self.index_granularity;
+                // Encode the values as usual, keeping the payloads in order.
+                self.values = payloads.iter().map(|&(v, _)| v).collect();
+                Some(payloads)
+            }
+            None if !self.unreduced => {
+                // Already sorted and distinct, and in range if the caller
+                // kept their promise.
+                debug_assert!(self.values.last().map_or(true, |&v| v < np));
+                None
+            }
+            None => {
+                self.values.par_iter_mut().for_each(|v| *v %= np);
+
+                status.stage("Resort");
+                self.values.par_sort_unstable();
+
+                // Distinct hashes may still collide once reduced mod n*p.
+                status.stage("Deduplicate collisions");
+                self.values.dedup();
+                None
+            }
+        };
+
+        if let Some(target) = self.index_target {
+            let g = recommend_index_granularity(self.values.len() as u64, self.p, target);
+            self.index_granularity = g as usize;
+            if g > 0 {
+                status.message(&format!("Chose index granularity {}", g));
+            } else {
+                status.message(&format!(
+                    "Chose no index, as {} items are few enough to scan",
+                    self.values.len()
+                ));
+            }
+        }
+
+        // Only the implied entry at the start of the data remains, so every
+        // query scans from there.  Fine for a handful of items.
+        if self.index_bits.is_none()
+            && self.index_granularity > 0
+            && self.index_granularity >= self.values.len()
+        {
+            status.message(&format!(
+                "Index granularity {} is not below {} items, so no index will be written",
+                self.index_granularity,
+                self.values.len()
+            ));
+        }
+
+        let index_points = match self.index_bits {
+            Some(bits) => self.values.len() / granularity_for_bits(self.p, bits) as usize,
+            None => self
+                .values
+                .len()
+                .checked_div(self.index_granularity)
+                .unwrap_or(0),
+        };
         let mut index: Vec<(u64, u64)> = Vec::with_capacity(index_points);
 
+        let mut total_bits: u64 = 0;
+
+        // Bit positions in the index are absolute, so account for the header.
+        if self.header {
+            self.io.write_all(&header_magic_for(self.order))?;
+            self.order.write_to(&mut self.io, self.n)?;
+            self.order.write_to(&mut self.io, self.p)?;
+            total_bits += GCS_HEADER_LEN * 8;
+        }
+
+        let base = self.shard_base.unwrap_or(0);
+        if self.values.first().map_or(false, |&v| v < base) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Shard holds a value below its base",
+            ));
+        }
+
         let mut encoder = GolombEncoder::new(self.io, self.p);
+        let mut last_index_bits = total_bits;
 
-        status.stage("Encode");
+        let mut stats = QuotientStats::default();
 
-        let mut total_bits: u64 = 0;
+        status.stage("Encode");
 
-        for (i, pair) in iter::once(&(0 as u64))
+        for (i, pair) in iter::once(&base)
             .chain(self.values.iter())
             .zip(self.values.iter())
             .enumerate()
         {
-            total_bits += encoder.encode(pair.1 - pair.0)? as u64;
+            let delta = pair.1 - pair.0;
+            total_bits += encoder.encode(delta)? as u64;
 
-            if self.index_granularity > 0 && i > 0 && i % self.index_granularity == 0 {
+            if self.stats {
+                stats.add(delta / self.p);
+            }
+
+            let index_point = match self.index_bits {
+                Some(bits) => i > 0 && total_bits - last_index_bits >= bits,
+                None => self.index_granularity > 0 && i > 0 && i % self.index_granularity == 0,
+            };
+            if index_point {
+                index.push((*pair.1, total_bits));
+                last_index_bits = total_bits;
+            }
+
+            // Payload ranks count index points, so they can't have this one.
+            if i == 0
+                && (self.index_granularity > 0 || self.index_bits.is_some())
+                && payloads.is_none()
+                && delta / self.p >= LONG_LEADING_RUN
+            {
                 index.push((*pair.1, total_bits));
             }
 
@@ -122,29 +777,144 @@ impl<T: io::Write> GCSBuilder<T> {
         // encode a delimiting zero
         total_bits += encoder.encode(0)? as u64;
 
-        let end_of_data = (total_bits + encoder.finish()? as u64) / 8;
+        if self.stats && stats.count > 0 {
+            status.finish_stage();
+            stats.print(status);
+        }
+
+        // finish pads the last byte and returns how many bits that took, zero
+        // if it was already full, so this should always land on a byte.
+        let total_bits = total_bits + encoder.finish()? as u64;
+        if total_bits % 8 != 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Encoded data doesn't end on a byte boundary",
+            ));
+        }
+        let end_of_data = total_bits / 8;
 
         self.io = encoder.into_inner();
 
         status.stage("Index");
         // Write the index: pairs of u64's (value, bit index)
         for &(v, pos) in &index {
-            self.io.write_u64::<BigEndian>(v)?;
-            self.io.write_u64::<BigEndian>(pos)?;
+            self.order.write_to(&mut self.io, v)?;
+            self.order.write_to(&mut self.io, pos)?;
         }
         status.finish_stage();
 
+        let payload_size = if let Some(ref payloads) = payloads {
+            status.stage("Payloads");
+            let width = self.payload_width;
+            for &(_, payload) in payloads.iter() {
+                self.order.write_uint_to(&mut self.io, payload, width)?;
+            }
+            self.order.write_to(&mut self.io, u64::from(width))?;
+            self.order
+                .write_to(&mut self.io, self.index_granularity as u64)?;
+            self.io.write_all(GCS_PAYLOAD_MAGIC)?;
+            status.finish_stage();
+
+            self.payload_size(payloads.len() as u64)
+        } else {
+            0
+        };
+
+        if let Some(base) = self.shard_base {
+            self.order.write_to(&mut self.io, base)?;
+            self.order
+                .write_to(&mut self.io, self.values.len() as u64)?;
+            self.io.write_all(GCS_SHARD_MAGIC)?;
+        }
+
+        if let Endian::Little = self.digest_order {
+            self.order.write_to(&mut self.io, 1)?;
+            self.io.write_all(GCS_DIGEST_MAGIC)?;
+        }
+
+        let data_size = if self.header {
+            end_of_data - GCS_HEADER_LEN
+        } else {
+            end_of_data
+        };
+        let index_size = index.len() as u64 * 16;
+        status.message(&format!(
+            "Data: {} bytes, index: {} bytes ({} entries), footer: 40 bytes",
+            data_size,
+            index_size,
+            index.len()
+        ));
+        if payload_size > 0 {
+            status.message(&format!("Payloads: {} bytes", payload_size));
+        }
+        if !self.values.is_empty() {
+            status.message(&item_bits_summary(
+                data_size,
+                self.values.len() as u64,
+                self.p,
+            ));
+        }
+
+        if index_size > data_size {
+            status.warning(
+                "index is larger than the data it indexes, consider a larger index granularity",
+            );
+        }
+
         // Write our footer
         // N, P, index position in bytes, index size in entries [magic]
         // 5*8=40 bytes
-        self.io.write_u64::<BigEndian>(self.n)?;
-        self.io.write_u64::<BigEndian>(self.p)?;
-        self.io.write_u64::<BigEndian>(end_of_data as u64)?;
-        self.io.write_u64::<BigEndian>(index.len() as u64)?;
-        self.io.write_all(GCS_MAGIC)?;
+        self.order.write_to(&mut self.io, self.n)?;
+        self.order.write_to(&mut self.io, self.p)?;
+        self.order.write_to(&mut self.io, end_of_data as u64)?;
+        self.order.write_to(&mut self.io, index.len() as u64)?;
+        self.io.write_all(&magic_for(self.order))?;
         self.io.flush()?;
 
-        Ok(())
+        Ok(self.io)
+    }
+}
+
+/// Outcome of a bounded lookup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    Present,
+    Absent,
+    /// The step budget ran out before the answer was known.
+    Indeterminate,
+}
+
+/// Where `GCSReader::exists_detailed` found a member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitSource {
+    /// The target is the value of an index point, so nothing was decoded.
+    Index,
+    /// The target was decoded scanning forward from an index point.
+    Scan,
+}
+
+/// Outcome of `GCSReader::exists_detailed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existence {
+    Present(HitSource),
+    Absent,
+}
+
+/// What a single lookup found.
+struct Found {
+    lookup: Lookup,
+    /// How a present target was found.
+    source: Option<HitSource>,
+    payload: Option<u64>,
+}
+
+impl Found {
+    fn absent() -> Self {
+        Found {
+            lookup: Lookup::Absent,
+            source: None,
+            payload: None,
+        }
     }
 }
 
@@ -154,83 +924,792 @@ pub struct GCSReader<R> {
     pub n: u64,
     pub p: u64,
     end_of_data: u64,
+    start_of_data: u64,
     index_len: u64,
     index: Vec<(u64, u64)>,
     log2p: u8,
+    pow2: bool,
+    order: Endian,
+    digest_order: Endian,
+    version: u32,
+    payloads: Option<PayloadRegion>,
+    shard: Option<ShardRange>,
+    last_query: QueryStats,
 }
 
-impl<R: io::Read + io::Seek> GCSReader<R> {
+/// How an opened database was built, from `GCSReader::params`.  The hash
+/// isn't recorded in the file, so it can't be recovered here.
+#[derive(Debug, Clone, Copy)]
+pub struct GcsParams {
+    pub n: u64,
+    /// The false-positive rate, 1-in-p.
+    pub p: u64,
+    /// Members per index point: exact for databases with payloads, which
+    /// record it, otherwise members over the number of index entries.  Zero if
+    /// there's no index.
+    pub index_granularity: u64,
+    pub index_len: u64,
+    /// Format version from the footer magic.
+    pub version: u32,
+    pub byte_order: Endian,
+    /// Byte order keys' digests were truncated in to make the values.
+    pub digest_order: Endian,
+    pub header: bool,
+    /// Bytes per payload, if the database has them.
+    pub payload_width: Option<u8>,
+    /// Bytes of encoded data, not counting the header.
+    pub data_bytes: u64,
+    /// Set if the database is a shard of a larger one.
+    pub shard: Option<ShardRange>,
+}
+
+/// What the last single lookup cost, from `GCSReader::last_query_stats`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QueryStats {
+    /// Seeks into the data, at most one per lookup.
+    pub seeks: u64,
+    /// Deltas decoded past the index point.
+    pub steps: u64,
+    /// Bits of data consumed decoding them.
+    pub bits: u64,
+}
+
+/// Which values a shard holds, from its trailer.
+#[derive(Debug, Clone, Copy)]
+pub struct ShardRange {
+    /// The value its first member is encoded from.
+    pub base: u64,
+    /// Members in the shard, where the footer's n is the whole database's.
+    pub count: u64,
+}
+
+/// Where a reader finds member payloads.
+#[derive(Debug)]
+struct PayloadRegion {
+    start: u64,
+    count: u64,
+    width: u8,
+    index_granularity: u64,
+}
+
+impl<R: io::Read> GCSReader<R> {
     pub fn new(inner: R) -> Self {
         Self {
             inner: BitReader::new(inner),
             n: 0,
             p: 0,
             end_of_data: 0,
+            start_of_data: 0,
             index_len: 0,
             index: Vec::with_capacity(0),
             log2p: 0,
+            pow2: false,
+            order: Endian::Big,
+            digest_order: Endian::Big,
+            version: 0,
+            payloads: None,
+            shard: None,
+            last_query: QueryStats::default(),
         }
     }
 
-    pub fn initialize(&mut self) -> io::Result<()> {
+    /// Read N and P from the leading header instead of the footer, for a
+    /// source that can't seek, such as a pipe.  Only `for_each_streamed`
+    /// works after this: the index and anything else past the data are out
+    /// of reach.  Shards keep their base past the data too, so can't be
+    /// streamed.
+    pub fn initialize_streaming(&mut self) -> io::Result<()> {
         let io = self.inner.get_mut();
-        io.seek(SeekFrom::End(-40))?;
+        let mut hdr = [0; 8];
+        io.read_exact(&mut hdr)
+            .map_err(|e| context(e, "header", 0))?;
+        let order = parse_header_magic(&hdr).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "No header: only databases built with --header can be streamed",
+            )
+        })?;
+        let (n, p) = read_pair(order, io).map_err(|e| context(e, "header", 8))?;
+        if p == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt header"));
+        }
+
+        self.order = order;
+        self.n = n;
+        self.p = p;
+        self.log2p = remainder_bits(p);
+        self.pow2 = p.is_power_of_two();
+        self.start_of_data = GCS_HEADER_LEN;
+
+        Ok(())
+    }
+
+    /// Call `f` on each member in `[from, to)`, in ascending order, decoding
+    /// forward from just after the header.  Stops at the terminator or `to`
+    /// without reading the rest of the source.
+    pub fn for_each_streamed<F>(&mut self, from: u64, to: u64, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64) -> io::Result<()>,
+    {
+        let mut last = 0;
+        let mut at_start = true;
+
+        loop {
+            let diff = self.read_delta()?;
+
+            // End of data, unless this is member 0
+            if diff == 0 && !(at_start && self.leading_zero_is_member()) {
+                break;
+            }
+            at_start = false;
+
+            last += diff;
+            if last >= to {
+                break;
+            }
+            if last >= from {
+                f(last)?;
+            }
+        }
+
+        Ok(())
+    }
 
-        self.n = io.read_u64::<BigEndian>()?;
-        self.p = io.read_u64::<BigEndian>()?;
+    /// Whether a zero delta read first from the implied entry is a member
+    /// equal to the base, rather than the terminator of an empty set.  The
+    /// data can't say: for small p the padding after a lone terminator fits
+    /// another code.  But any set with members encodes the first of them
+    /// before its terminator.  A shard's footer has the whole database's n,
+    /// so it records its own count.
+    fn leading_zero_is_member(&self) -> bool {
+        match self.shard {
+            Some(shard) => shard.count > 0,
+            None => self.n > 0,
+        }
+    }
 
-        self.log2p = (self.p as f64).log2().ceil().trunc() as u8;
+    fn read_delta(&mut self) -> io::Result<u64> {
+        let mut q: u64 = 0;
+        while self.inner.read_bit()? == 1 {
+            q += 1;
+            // Every value is below n*p, so no gap has a quotient above n.
+            if q > self.n {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Corrupt data: unary run longer than any gap",
+                ));
+            }
+        }
+        self.last_query.bits += q + 1 + u64::from(self.log2p);
 
-        self.end_of_data = io.read_u64::<BigEndian>()?;
-        self.index_len = io.read_u64::<BigEndian>()?;
+        let r = if self.log2p > 0 {
+            self.inner.read_bits(self.log2p)?
+        } else {
+            0
+        };
 
+        if self.pow2 {
+            Ok((q << self.log2p) | r)
+        } else {
+            Ok(q * self.p + r)
+        }
+    }
+}
+
+impl<R: io::Read + io::Seek> GCSReader<R> {
+    pub fn initialize(&mut self) -> io::Result<()> {
+        let io = self.inner.get_mut();
+        if io.seek(SeekFrom::End(0))? < 40 {
+            return Err(FormatError::BadMagic.into());
+        }
+        let footer_pos = io.seek(SeekFrom::End(-40))?;
+
+        let mut footer = [0; 40];
+        io.read_exact(&mut footer)
+            .map_err(|e| context(e, "footer", footer_pos))?;
+
+        // The magic says how to read the rest.
         let mut hdr = [0; 8];
-        io.read_exact(&mut hdr)?;
-        if hdr != *GCS_MAGIC {
-            return Err(Error::new(ErrorKind::Other, "Not a GCS file"));
+        hdr.copy_from_slice(&footer[32..]);
+        let order = match parse_magic(&hdr) {
+            None => return Err(FormatError::BadMagic.into()),
+            Some((_, v)) if v > GCS_VERSION => {
+                return Err(FormatError::UnsupportedVersion(v).into())
+            }
+            Some((order, v)) => {
+                self.version = v;
+                order
+            }
+        };
+        self.order = order;
+
+        let mut footer = &footer[..32];
+        self.n = order.read_from(&mut footer)?;
+        self.p = order.read_from(&mut footer)?;
+
+        // Queries reduce hashes mod N * P, so both must multiply cleanly.
+        if self.p == 0 || self.n.checked_mul(self.p).is_none() {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt footer"));
+        }
+
+        self.log2p = remainder_bits(self.p);
+        self.pow2 = self.p.is_power_of_two();
+
+        self.end_of_data = order.read_from(&mut footer)?;
+        self.index_len = order.read_from(&mut footer)?;
+
+        // The index sits between the end of the data and the footer, so its
+        // size is bounded by the file itself.
+        if self.end_of_data > footer_pos || self.index_len > (footer_pos - self.end_of_data) / 16 {
+            return Err(Error::new(ErrorKind::Other, "Corrupt index"));
+        }
+
+        if self.end_of_data >= GCS_HEADER_LEN {
+            io.seek(SeekFrom::Start(0))?;
+            io.read_exact(&mut hdr)
+                .map_err(|e| context(e, "header", 0))?;
+            if parse_header_magic(&hdr).is_some() {
+                let (n, p) = read_pair(order, io).map_err(|e| context(e, "header", 8))?;
+                if n != self.n || p != self.p {
+                    return Err(Error::new(ErrorKind::Other, "Header does not match footer"));
+                }
+                self.start_of_data = GCS_HEADER_LEN;
+            }
+        }
+
+        // Trailers sit between the index and the footer, the digest order's
+        // last and the shard's before it.
+        let index_end = self.end_of_data + self.index_len * 16;
+        let mut trailers_end = footer_pos;
+        if trailers_end - index_end >= GCS_DIGEST_TRAILER_LEN {
+            let trailer_pos = trailers_end - GCS_DIGEST_TRAILER_LEN;
+            io.seek(SeekFrom::Start(trailer_pos))?;
+            let little: u64 = order
+                .read_from(io)
+                .map_err(|e| context(e, "digest trailer", trailer_pos))?;
+            io.read_exact(&mut hdr)
+                .map_err(|e| context(e, "digest trailer", trailer_pos + 8))?;
+
+            if hdr == *GCS_DIGEST_MAGIC {
+                if little != 1 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Corrupt digest trailer"));
+                }
+                self.digest_order = Endian::Little;
+                trailers_end = trailer_pos;
+            }
+        }
+        if trailers_end - index_end >= GCS_SHARD_TRAILER_LEN {
+            let trailer_pos = trailers_end - GCS_SHARD_TRAILER_LEN;
+            io.seek(SeekFrom::Start(trailer_pos))?;
+            let (base, count) =
+                read_pair(order, io).map_err(|e| context(e, "shard trailer", trailer_pos))?;
+            io.read_exact(&mut hdr)
+                .map_err(|e| context(e, "shard trailer", trailer_pos + 16))?;
+
+            if hdr == *GCS_SHARD_MAGIC {
+                self.shard = Some(ShardRange { base, count });
+                trailers_end = trailer_pos;
+            }
         }
+        let base = self.shard.map_or(0, |shard| shard.base);
 
         io.seek(SeekFrom::Start(self.end_of_data))?;
 
         // slurp in the index.
         self.index.reserve(1 + self.index_len as usize);
-        self.index.push((0, 0)); // implied
+        self.index
+            .push(Self::implied_entry(base, self.start_of_data));
+
+        let end_of_data = self.end_of_data;
+        for i in 0..self.index_len {
+            let entry = read_pair(order, io)
+                .map_err(|e| context(e, format!("index entry {}", i), end_of_data + i * 16))?;
+
+            // Searching relies on both columns being sorted, so a corrupt
+            // entry here would otherwise mean quietly wrong answers.
+            let prev = self.index[self.index.len() - 1];
+            if entry.0 <= prev.0 || entry.1 <= prev.1 || entry.1 >= self.end_of_data * 8 {
+                return Err(Error::new(ErrorKind::InvalidData, "Corrupt index"));
+            }
+
+            self.index.push(entry);
+        }
+
+        // Anything else between the index and the footer may be payloads.
+        if trailers_end - index_end >= GCS_PAYLOAD_TRAILER_LEN {
+            let trailer_pos = trailers_end - GCS_PAYLOAD_TRAILER_LEN;
+            io.seek(SeekFrom::Start(trailer_pos))?;
+            let (width, index_granularity) =
+                read_pair(order, io).map_err(|e| context(e, "payload trailer", trailer_pos))?;
+            io.read_exact(&mut hdr)
+                .map_err(|e| context(e, "payload trailer", trailer_pos + 16))?;
+
+            if hdr == *GCS_PAYLOAD_MAGIC {
+                let len = trailer_pos - index_end;
+                if width == 0 || width > 8 || len % width != 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "Corrupt payloads"));
+                }
 
-        for _ in 0..self.index_len {
-            self.index
-                .push((io.read_u64::<BigEndian>()?, io.read_u64::<BigEndian>()?));
+                self.payloads = Some(PayloadRegion {
+                    start: index_end,
+                    count: len / width,
+                    width: width as u8,
+                    index_granularity,
+                });
+            }
         }
 
         Ok(())
     }
 
+    /// Read the whole file once so the OS page cache holds it before queries
+    /// start.  Returns the number of bytes read.
+    pub fn warm(&mut self) -> io::Result<u64> {
+        let io = self.inner.get_mut();
+        io.seek(SeekFrom::Start(0))?;
+        io::copy(io, &mut io::sink())
+    }
+
     pub fn exists(&mut self, target: u64) -> io::Result<bool> {
+        Ok(self.find(target, None, false)?.lookup == Lookup::Present)
+    }
+
+    /// Like `exists`, but also say whether a member was matched by the index
+    /// itself or found while scanning the data.
+    pub fn exists_detailed(&mut self, target: u64) -> io::Result<Existence> {
+        Ok(match self.find(target, None, false)?.source {
+            Some(source) => Existence::Present(source),
+            None => Existence::Absent,
+        })
+    }
+
+    /// Seeks, steps and bits taken by the most recent `exists` or
+    /// `lookup_bounded`.
+    /// Batch and range reads add to its bit count without resetting it.
+    pub fn last_query_stats(&self) -> QueryStats {
+        self.last_query
+    }
+
+    /// The (value, bit position) pairs stored in the index, without the
+    /// implied entry at the start of the data.
+    pub fn index_entries(&self) -> &[(u64, u64)] {
+        &self.index[1..]
+    }
+
+    /// The parameters the database was built with, once initialized.
+    pub fn params(&self) -> GcsParams {
+        let index_granularity = match self.payloads {
+            Some(ref region) => region.index_granularity,
+            None => self
+                .shard
+                .map_or(self.n, |shard| shard.count)
+                .checked_div(self.index_len)
+                .unwrap_or(0),
+        };
+
+        GcsParams {
+            n: self.n,
+            p: self.p,
+            index_granularity,
+            index_len: self.index_len,
+            version: self.version,
+            byte_order: self.order,
+            digest_order: self.digest_order,
+            header: self.start_of_data > 0,
+            payload_width: self.payloads.as_ref().map(|region| region.width),
+            data_bytes: self.end_of_data - self.start_of_data,
+            shard: self.shard,
+        }
+    }
+
+    /// Byte order the keys' digests were truncated in: big-endian unless the
+    /// database records otherwise.
+    pub fn digest_order(&self) -> Endian {
+        self.digest_order
+    }
+
+    pub fn has_payloads(&self) -> bool {
+        self.payloads.is_some()
+    }
+
+    /// Like `exists`, but give up with `Lookup::Indeterminate` rather than
+    /// decode more than `max_steps` values past the nearest index point, and
+    /// also return the payload of a present target if the database has them.
+    pub fn lookup_bounded(
+        &mut self,
+        target: u64,
+        max_steps: Option<u64>,
+    ) -> io::Result<(Lookup, Option<u64>)> {
+        let found = self.find(target, max_steps, true)?;
+        Ok((found.lookup, found.payload))
+    }
+
+    /// The entry every search falls back to below the first stored one: value
+    /// `base` at the first bit of the data.  Builders encode the first member
+    /// as a delta from the base, 0 unless this is a shard, so decoding from
+    /// here reproduces every value.  It marks where decoding starts, not a
+    /// member: stored entries are always above the base.
+    fn implied_entry(base: u64, start_of_data: u64) -> (u64, u64) {
+        (base, start_of_data * 8)
+    }
+
+    /// Find `h` in the index, with the same result as `binary_search`.
+    ///
+    /// Index values are hashes and so roughly uniform, which lets us guess
+    /// where `h` should be and gallop outwards from there to bracket it.  If
+    /// the guess is poor this costs at most about twice a binary search.
+    fn search_index(&self, h: u64) -> Result<usize, usize> {
+        let index = &self.index;
+        let len = index.len();
+
+        if len < INTERPOLATION_MIN_LEN || h <= index[0].0 || h >= index[len - 1].0 {
+            return index.binary_search_by_key(&h, |&(v, _p)| v);
+        }
+
+        let fraction = (h - index[0].0) as f64 / (index[len - 1].0 - index[0].0) as f64;
+        let guess = (fraction * (len - 1) as f64) as usize;
+
+        let (lo, hi) = if index[guess].0 < h {
+            let mut lo = guess + 1;
+            let mut step = 1;
+            loop {
+                let probe = guess + step;
+                if probe >= len {
+                    break (lo, len);
+                }
+                if index[probe].0 >= h {
+                    break (lo, probe + 1);
+                }
+                lo = probe + 1;
+                step *= 2;
+            }
+        } else {
+            let mut hi = guess + 1;
+            let mut step = 1;
+            loop {
+                if step > guess {
+                    break (0, hi);
+                }
+                let probe = guess - step;
+                if index[probe].0 <= h {
+                    break (probe, hi);
+                }
+                hi = probe;
+                step *= 2;
+            }
+        };
+
+        index[lo..hi]
+            .binary_search_by_key(&h, |&(v, _p)| v)
+            .map(|i| i + lo)
+            .map_err(|i| i + lo)
+    }
+
+    fn find(
+        &mut self,
+        target: u64,
+        max_steps: Option<u64>,
+        want_payload: bool,
+    ) -> io::Result<Found> {
+        self.last_query = QueryStats::default();
+        // An empty set has nothing to reduce by, and no members to find.
+        if self.n == 0 {
+            return Ok(Found::absent());
+        }
         let h = target % (self.n * self.p);
 
-        let entry = match self.index.binary_search_by_key(&h, |&(v, _p)| v) {
-            Ok(_) => return Ok(true),
-            Err(e) => self.index[e.saturating_sub(1)],
+        // Index entry k marks member k*granularity, except the implied first
+        // entry, which sits just before member 0.
+        let entry_pos = match self.search_index(h) {
+            Ok(0) => 0,
+            Ok(e) => {
+                let payload = if want_payload {
+                    self.read_payload(e as u64, 0)?
+                } else {
+                    None
+                };
+                return Ok(Found {
+                    lookup: Lookup::Present,
+                    source: Some(HitSource::Index),
+                    payload,
+                });
+            }
+            Err(e) => e.saturating_sub(1),
         };
+        let entry = self.index[entry_pos];
         let mut last = entry.0;
         let bit_pos = entry.1;
 
         self.inner.seek(SeekFrom::Start(bit_pos))?;
+        self.last_query.seeks += 1;
 
-        while last < h {
-            let mut diff = 0;
-            while self.inner.read_bit()? == 1 {
-                diff += self.p;
+        let mut steps = 0;
+        // Member 0 is only known by reading its leading zero delta.
+        let mut at_start = entry_pos == 0;
+
+        while last < h || at_start {
+            if max_steps.map_or(false, |max| steps >= max) {
+                return Ok(Found {
+                    lookup: Lookup::Indeterminate,
+                    source: None,
+                    payload: None,
+                });
             }
+            steps += 1;
+            self.last_query.steps += 1;
 
-            diff += self.inner.read_bits(self.log2p)?;
+            // Every bit read so far this query came after bit_pos.
+            let read_pos = bit_pos + self.last_query.bits;
+            let diff = self
+                .read_delta()
+                .map_err(|e| context(e, format!("data bit {}", read_pos), read_pos / 8))?;
             last += diff;
 
-            // End of file
-            if diff == 0 {
+            // End of file, unless this is member 0
+            if diff == 0 && !(at_start && self.leading_zero_is_member()) {
+                return Ok(Found::absent());
+            }
+            at_start = false;
+        }
+
+        if last == h {
+            let payload = if want_payload {
+                self.read_payload(entry_pos as u64, steps)?
+            } else {
+                None
+            };
+            Ok(Found {
+                lookup: Lookup::Present,
+                source: Some(HitSource::Scan),
+                payload,
+            })
+        } else {
+            Ok(Found::absent())
+        }
+    }
+
+    /// Read the payload of the member `steps` past index entry `entry_pos`,
+    /// if there are payloads.
+    fn read_payload(&mut self, entry_pos: u64, steps: u64) -> io::Result<Option<u64>> {
+        let (start, count, width, rank) = match self.payloads {
+            Some(ref region) if entry_pos == 0 => (
+                region.start,
+                region.count,
+                region.width,
+                steps.saturating_sub(1),
+            ),
+            Some(ref region) => (
+                region.start,
+                region.count,
+                region.width,
+                // A corrupt trailer could give any granularity at all.
+                entry_pos
+                    .checked_mul(region.index_granularity)
+                    .and_then(|r| r.checked_add(steps))
+                    .unwrap_or(u64::MAX),
+            ),
+            None => return Ok(None),
+        };
+
+        if rank >= count {
+            return Err(Error::new(ErrorKind::InvalidData, "Corrupt payloads"));
+        }
+
+        let order = self.order;
+        let io = self.inner.get_mut();
+        io.seek(SeekFrom::Start(start + rank * u64::from(width)))?;
+        order.read_uint_from(io, width).map(Some)
+    }
+
+    /// Check many targets at once, returning results in the same order.
+    ///
+    /// Targets are reduced and sorted so the data is decoded in a single
+    /// forward pass, only seeking when the index can skip ahead.
+    pub fn exists_batch(&mut self, targets: &[u64]) -> io::Result<Vec<bool>> {
+        if self.n == 0 {
+            return Ok(vec![false; targets.len()]);
+        }
+        let np = self.n * self.p;
+        let mut order: Vec<(u64, usize)> = targets
+            .iter()
+            .enumerate()
+            .map(|(i, &t)| (t % np, i))
+            .collect();
+        order.sort_unstable();
+
+        let mut results = vec![false; targets.len()];
+        let mut last = 0;
+        let mut positioned = false;
+        let mut end = false;
+        let mut at_start = false;
+
+        for &(h, i) in &order {
+            let entry_pos = match self.search_index(h) {
+                Ok(0) => 0,
+                Ok(_) => {
+                    results[i] = true;
+                    continue;
+                }
+                Err(e) => e.saturating_sub(1),
+            };
+            let entry = self.index[entry_pos];
+
+            if !positioned || entry.0 > last {
+                self.inner.seek(SeekFrom::Start(entry.1))?;
+                last = entry.0;
+                positioned = true;
+                end = false;
+                at_start = entry_pos == 0;
+            }
+
+            while !end && (last < h || at_start) {
+                let diff = self.read_delta()?;
+                last += diff;
+
+                // End of file, unless this is member 0
+                end = diff == 0 && !(at_start && self.leading_zero_is_member());
+                at_start = false;
+            }
+
+            // An empty set ends with last still at 0.
+            results[i] = !end && last == h;
+        }
+
+        Ok(results)
+    }
+
+    /// Call `f` on each member in `[from, to)`, in ascending order, decoding
+    /// forward from the nearest index point at or below `from`.
+    pub fn for_each_in_range<F>(&mut self, from: u64, to: u64, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64) -> io::Result<()>,
+    {
+        if self.n == 0 {
+            return Ok(());
+        }
+        let pos = match self.search_index(from) {
+            Ok(i) => i,
+            Err(e) => e.saturating_sub(1),
+        };
+        let (mut last, bit_pos) = self.index[pos];
+
+        self.inner.seek(SeekFrom::Start(bit_pos))?;
+
+        // The first entry is implied rather than a member.
+        if pos > 0 && last >= from && last < to {
+            f(last)?;
+        }
+        let mut at_start = pos == 0;
+
+        loop {
+            let diff = self.read_delta()?;
+
+            // End of file, unless this is member 0
+            if diff == 0 && !(at_start && self.leading_zero_is_member()) {
+                break;
+            }
+            at_start = false;
+
+            last += diff;
+            if last >= to {
+                break;
+            }
+            if last >= from {
+                f(last)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a copy of the database to `out` with an index every
+    /// `index_granularity` members, placed just as a fresh build would place
+    /// them.  The data is copied byte for byte and decoded only to find the
+    /// new index points.  Returns the number of index entries written.
+    pub fn reindex<W: io::Write>(
+        &mut self,
+        out: &mut W,
+        index_granularity: u64,
+    ) -> io::Result<u64> {
+        {
+            let io = self.inner.get_mut();
+            io.seek(SeekFrom::Start(0))?;
+            let copied = io::copy(&mut io::Read::take(io, self.end_of_data), out)?;
+            if copied != self.end_of_data {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Data is truncated"));
+            }
+        }
+
+        let mut index: Vec<(u64, u64)> = Vec::new();
+        let (mut last, mut bit_pos) = self.index[0];
+        self.inner.seek(SeekFrom::Start(bit_pos))?;
+
+        for i in 0.. {
+            let diff = self.read_delta()?;
+
+            // End of file, unless this is member 0
+            if diff == 0 && !(i == 0 && self.leading_zero_is_member()) {
                 break;
             }
+
+            last += diff;
+            bit_pos += diff / self.p + 1 + u64::from(self.log2p);
+
+            // As the builder does it, including the extra point after a long
+            // leading run.
+            if index_granularity > 0 && i > 0 && i % index_granularity == 0 {
+                index.push((last, bit_pos));
+            }
+            if i == 0
+                && index_granularity > 0
+                && self.payloads.is_none()
+                && diff / self.p >= LONG_LEADING_RUN
+            {
+                index.push((last, bit_pos));
+            }
+        }
+
+        let order = self.order;
+        for &(v, pos) in &index {
+            order.write_to(out, v)?;
+            order.write_to(out, pos)?;
+        }
+
+        if let Some(ref region) = self.payloads {
+            let io = self.inner.get_mut();
+            let len = region.count * u64::from(region.width);
+            io.seek(SeekFrom::Start(region.start))?;
+            if io::copy(&mut io::Read::take(io, len), out)? != len {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Payloads are truncated",
+                ));
+            }
+            // Ranks are worked out from index positions, so the trailer has
+            // to follow the new granularity.
+            order.write_to(out, u64::from(region.width))?;
+            order.write_to(out, index_granularity)?;
+            out.write_all(GCS_PAYLOAD_MAGIC)?;
+        }
+
+        if let Some(shard) = self.shard {
+            order.write_to(out, shard.base)?;
+            order.write_to(out, shard.count)?;
+            out.write_all(GCS_SHARD_MAGIC)?;
         }
 
-        Ok(last == h)
+        if let Endian::Little = self.digest_order {
+            order.write_to(out, 1)?;
+            out.write_all(GCS_DIGEST_MAGIC)?;
+        }
+
+        order.write_to(out, self.n)?;
+        order.write_to(out, self.p)?;
+        order.write_to(out, self.end_of_data)?;
+        order.write_to(out, index.len() as u64)?;
+        out.write_all(&magic_for(order))?;
+        out.flush()?;
+
+        Ok(index.len() as u64)
     }
 }
@@ -0,0 +1,120 @@
+//! A `Read + Seek` view of a remote database over HTTP range requests, so
+//! `query --url` can ask a file on a static host without downloading it.
+//!
+//! The footer, the index and each query's run of data are fetched on demand
+//! in fixed-size blocks, and recently used blocks are kept in an LRU cache.
+//! The server must honour `Range` and report the file's length.
+
+use std::cmp;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+use ureq::{Agent, AgentBuilder};
+
+use double::offset;
+
+const BLOCK_SIZE: u64 = 16 * 1024;
+const CACHE_BLOCKS: usize = 1024;
+
+pub struct HttpReader {
+    agent: Agent,
+    url: String,
+    len: u64,
+    pos: u64,
+    blocks: LruCache<u64, Vec<u8>>,
+}
+
+fn http_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl HttpReader {
+    pub fn new(url: &str) -> io::Result<Self> {
+        let agent = AgentBuilder::new().build();
+        let response = agent.head(url).call().map_err(http_error)?;
+        let len = response
+            .header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok())
+            .ok_or_else(|| http_error("server didn't give the database's length"))?;
+
+        Ok(Self {
+            agent,
+            url: url.to_owned(),
+            len,
+            pos: 0,
+            blocks: LruCache::new(NonZeroUsize::new(CACHE_BLOCKS).unwrap()),
+        })
+    }
+
+    fn fetch(&mut self, block: u64) -> io::Result<Vec<u8>> {
+        let start = block * BLOCK_SIZE;
+        let end = cmp::min(start + BLOCK_SIZE, self.len);
+
+        let response = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end - 1))
+            .call()
+            .map_err(http_error)?;
+        // A server that ignores Range would send the whole file every time.
+        if response.status() != 206 {
+            return Err(http_error("server doesn't support range requests"));
+        }
+
+        let mut data = Vec::with_capacity((end - start) as usize);
+        response
+            .into_reader()
+            .take(end - start)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != end - start {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "short range response",
+            ));
+        }
+
+        Ok(data)
+    }
+}
+
+impl Read for HttpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = self.pos / BLOCK_SIZE;
+        if !self.blocks.contains(&block) {
+            let data = self.fetch(block)?;
+            self.blocks.put(block, data);
+        }
+
+        let data = self.blocks.get(&block).unwrap();
+        let offset = (self.pos - block * BLOCK_SIZE) as usize;
+        let n = cmp::min(buf.len(), data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => offset(self.len, off),
+            SeekFrom::Current(off) => offset(self.pos, off),
+        };
+
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
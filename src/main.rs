@@ -1,17 +1,23 @@
+use std::ffi::OsStr;
+use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
 use std::io::SeekFrom;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, Cursor};
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
 use std::path::Path;
+use std::str;
 use std::str::FromStr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{thread, time};
 
 extern crate blake2;
+extern crate bytecount;
 extern crate byteorder;
 extern crate md5;
-extern crate memchr;
+extern crate rand;
 extern crate rayon;
 extern crate sha1;
 extern crate sha2;
@@ -19,176 +25,1616 @@ extern crate sha2;
 extern crate clap;
 
 extern crate bitrw;
+extern crate ctrlc;
+extern crate is_terminal;
 extern crate linereader;
+extern crate lru;
+extern crate memmap2;
+extern crate owo_colors;
+extern crate ureq;
 
-use byteorder::{BigEndian, ReadBytesExt};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use is_terminal::IsTerminal;
 use linereader::LineReader;
-use memchr::Memchr;
+use lru::LruCache;
+use owo_colors::OwoColorize;
+use rand::Rng;
 use sha1::Digest;
 
+mod double;
 mod gcs;
+mod http;
+mod mmap;
+mod serve;
 mod status;
 
-use gcs::{GCSBuilder, GCSReader};
-use status::Status;
+use double::DoubleReader;
+use gcs::{Existence, GCSBuilder, GCSReader, GcsSizeEstimate, HitSource, Lookup};
+use http::HttpReader;
+use mmap::MmapWriter;
+use status::{PrintProgress, Status};
 
-#[derive(Debug)]
-pub enum HashType {
-    Hex,
-    Md5,
-    Sha1,
-    Sha2_256,
-    Sha2_512,
-    Blake2b,
+/// Turns a key into the value stored for it.  Implement this and add it
+/// to `HASHERS` to offer another hash.
+pub trait KeyHasher: Sync {
+    fn digest(&self, key: &[u8], endian: Endian) -> Result<u64, &'static str>;
+
+    /// Two independent values from one key, for double hashing.
+    fn digest_pair(&self, key: &[u8], endian: Endian) -> Result<(u64, u64), &'static str>;
+}
+
+/// The key is already a hex digest: its first 16 hex digits are used as the
+/// value, and anything after them is ignored.
+struct HexHasher;
+
+impl KeyHasher for HexHasher {
+    fn digest(&self, s: &[u8], endian: Endian) -> Result<u64, &'static str> {
+        if s.len() < 16 {
+            Err("hex keys need at least 16 hex digits")
+        } else {
+            // Hex digits spell out the digest bytes in order, so parsing
+            // them is already big-endian.
+            u64_from_hex(&s[0..16])
+                .map(|v| match endian {
+                    Endian::Big => v,
+                    Endian::Little => v.swap_bytes(),
+                })
+                .ok_or("hex keys must start with 16 hex digits")
+        }
+    }
+
+    /// 16 hex digits each.
+    fn digest_pair(&self, s: &[u8], endian: Endian) -> Result<(u64, u64), &'static str> {
+        if s.len() < 32 {
+            return Err("double hashing needs at least 32 hex digits");
+        }
+        let first = self.digest(&s[0..16], endian);
+        let second = self.digest(&s[16..32], endian);
+        first
+            .and_then(|first| second.map(|second| (first, second)))
+            .map_err(|_| "double hashing needs keys to start with 32 hex digits")
+    }
+}
+
+/// Any cryptographic digest, truncated to its leading bytes.
+struct DigestHasher<D>(PhantomData<fn() -> D>);
+
+impl<D: Digest> KeyHasher for DigestHasher<D> {
+    fn digest(&self, s: &[u8], endian: Endian) -> Result<u64, &'static str> {
+        endian.read_u64(D::digest(s).as_slice())
+    }
+
+    /// The first and second 8 bytes of the digest.
+    fn digest_pair(&self, s: &[u8], endian: Endian) -> Result<(u64, u64), &'static str> {
+        let digest = D::digest(s);
+        let first = endian.read_u64(&digest[0..8])?;
+        let second = endian.read_u64(&digest[8..])?;
+        Ok((first, second))
+    }
 }
 
+/// Every hash --hash can name.
+static HASHERS: &[(&str, &dyn KeyHasher)] = &[
+    ("hex", &HexHasher),
+    ("sha1", &DigestHasher::<sha1::Sha1>(PhantomData)),
+    ("sha256", &DigestHasher::<sha2::Sha256>(PhantomData)),
+    ("sha512", &DigestHasher::<sha2::Sha512>(PhantomData)),
+    ("md5", &DigestHasher::<md5::Md5>(PhantomData)),
+    ("blake2b", &DigestHasher::<blake2::Blake2b>(PhantomData)),
+];
+
+/// A hash from `HASHERS`, chosen by name.
+#[derive(Clone, Copy)]
+pub struct HashType(&'static dyn KeyHasher);
+
 impl FromStr for HashType {
     type Err = &'static str;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        HASHERS
+            .iter()
+            .find(|&&(name, _)| name == s)
+            .map(|&(_, hasher)| HashType(hasher))
+            .ok_or("no match")
+    }
+}
+
+/// Byte order used to turn the leading bytes of a digest into a u64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+impl FromStr for Endian {
+    type Err = &'static str;
+
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_ref() {
-            "hex" => Ok(HashType::Hex),
-            "md5" => Ok(HashType::Md5),
-            "sha1" => Ok(HashType::Sha1),
-            "sha256" => Ok(HashType::Sha2_256),
-            "sha512" => Ok(HashType::Sha2_512),
-            "blake2b" => Ok(HashType::Blake2b),
+            "be" => Ok(Endian::Big),
+            "le" => Ok(Endian::Little),
             _ => Err("no match"),
         }
     }
 }
 
+impl Endian {
+    /// The --endian value naming this order.
+    fn arg(self) -> &'static str {
+        match self {
+            Endian::Big => "be",
+            Endian::Little => "le",
+        }
+    }
+
+    fn read_u64(self, digest: &[u8]) -> Result<u64, &'static str> {
+        let mut digest = Cursor::new(digest);
+        match self {
+            Endian::Big => digest.read_u64::<BigEndian>(),
+            Endian::Little => digest.read_u64::<LittleEndian>(),
+        }
+        .map_err(|_| "digest is shorter than 8 bytes")
+    }
+
+    fn read_from<R: Read>(self, r: &mut R) -> io::Result<u64> {
+        match self {
+            Endian::Big => r.read_u64::<BigEndian>(),
+            Endian::Little => r.read_u64::<LittleEndian>(),
+        }
+    }
+
+    fn write_to<W: Write>(self, w: &mut W, v: u64) -> io::Result<()> {
+        match self {
+            Endian::Big => w.write_u64::<BigEndian>(v),
+            Endian::Little => w.write_u64::<LittleEndian>(v),
+        }
+    }
+
+    /// Read an unsigned integer of `nbytes`, 1 to 8.
+    fn read_uint_from<R: Read>(self, r: &mut R, nbytes: u8) -> io::Result<u64> {
+        match self {
+            Endian::Big => r.read_uint::<BigEndian>(nbytes as usize),
+            Endian::Little => r.read_uint::<LittleEndian>(nbytes as usize),
+        }
+    }
+
+    /// Write the low `nbytes` of `v`, 1 to 8.
+    fn write_uint_to<W: Write>(self, w: &mut W, v: u64, nbytes: u8) -> io::Result<()> {
+        match self {
+            Endian::Big => w.write_uint::<BigEndian>(v, nbytes as usize),
+            Endian::Little => w.write_uint::<LittleEndian>(v, nbytes as usize),
+        }
+    }
+}
+
 impl HashType {
-    fn digest(&self, s: &[u8]) -> Option<u64> {
-        match *self {
-            HashType::Hex => {
-                if s.len() < 16 {
-                    None
+    fn digest(&self, s: &[u8], endian: Endian) -> Result<u64, &'static str> {
+        self.0.digest(s, endian)
+    }
+
+    fn digest_pair(&self, s: &[u8], endian: Endian) -> Result<(u64, u64), &'static str> {
+        self.0.digest_pair(s, endian)
+    }
+}
+
+/// Normalisation applied to keys before hashing.
+#[derive(Debug, Clone, Copy)]
+pub enum Normalize {
+    AsciiLowercase,
+    /// Unicode lowercase, falling back to ASCII for invalid UTF-8.
+    Lowercase,
+}
+
+impl FromStr for Normalize {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "ascii-lowercase" => Ok(Normalize::AsciiLowercase),
+            "lowercase" => Ok(Normalize::Lowercase),
+            _ => Err("no match"),
+        }
+    }
+}
+
+impl Normalize {
+    fn apply(self, key: &[u8]) -> Vec<u8> {
+        match self {
+            Normalize::AsciiLowercase => key.to_ascii_lowercase(),
+            Normalize::Lowercase => match str::from_utf8(key) {
+                Ok(s) => s.to_lowercase().into_bytes(),
+                Err(_) => key.to_ascii_lowercase(),
+            },
+        }
+    }
+}
+
+/// How each input line becomes a key.  Create and query must agree on all
+/// of it, since only the digest byte order is recorded in the database.
+#[derive(Clone, Copy)]
+pub struct KeySpec {
+    hash: HashType,
+    /// Set if --endian was given, otherwise queries follow the database.
+    endian: Option<Endian>,
+    /// Hash only this 1-based field, split on the given delimiter.
+    field: Option<(u8, usize)>,
+    normalize: Option<Normalize>,
+    /// Hash keys with a trailing newline, as some other tools do.
+    append_newline: bool,
+    /// Records end in NUL rather than a newline, so keys may contain newlines.
+    null: bool,
+}
+
+impl KeySpec {
+    fn record_end(&self) -> u8 {
+        if self.null {
+            0
+        } else {
+            b'\n'
+        }
+    }
+
+    /// Strip the terminator from a raw record.  Newline-delimited records
+    /// also lose anything from a carriage return onwards.
+    fn trim_record<'a>(&self, record: &'a [u8]) -> &'a [u8] {
+        if self.null {
+            record.split(|b| *b == 0).next().unwrap()
+        } else {
+            record.split(|b| *b == b'\n' || *b == b'\r').next().unwrap()
+        }
+    }
+
+    /// Byte order to truncate digests in, big-endian unless told otherwise.
+    fn digest_order(&self) -> Endian {
+        self.endian.unwrap_or(Endian::Big)
+    }
+
+    /// This spec with the digest byte order a database records, or an error
+    /// if --endian asked for the other one.
+    fn for_database(&self, recorded: Endian) -> io::Result<KeySpec> {
+        match self.endian {
+            Some(given) if given != recorded => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Database was built with --endian {}, not {}",
+                    recorded.arg(),
+                    given.arg()
+                ),
+            )),
+            _ => Ok(KeySpec {
+                endian: Some(recorded),
+                ..*self
+            }),
+        }
+    }
+
+    fn digest(&self, line: &[u8]) -> Result<u64, &'static str> {
+        self.with_key(line, |key| self.hash.digest(key, self.digest_order()))
+    }
+
+    fn digest_pair(&self, line: &[u8]) -> Result<(u64, u64), &'static str> {
+        self.with_key(line, |key| self.hash.digest_pair(key, self.digest_order()))
+    }
+
+    /// Call `f` with the key a line stands for.
+    fn with_key<T, F>(&self, line: &[u8], f: F) -> Result<T, &'static str>
+    where
+        F: FnOnce(&[u8]) -> Result<T, &'static str>,
+    {
+        let key = match self.field {
+            Some((delimiter, n)) => line
+                .split(|b| *b == delimiter)
+                .nth(n - 1)
+                .ok_or("line has too few fields")?,
+            None => line,
+        };
+
+        if self.normalize.is_none() && !self.append_newline {
+            return f(key);
+        }
+
+        let mut key = match self.normalize {
+            Some(normalize) => normalize.apply(key),
+            None => key.to_vec(),
+        };
+        if self.append_newline {
+            key.push(b'\n');
+        }
+
+        f(&key)
+    }
+}
+
+const ESTIMATE_LIMIT: u64 = 1024 * 1024 * 16;
+
+/// Count the records in a file, or estimate them from `chunks` samples
+/// totalling `ESTIMATE_LIMIT` bytes if it's larger than that.
+///
+/// Evenly spaced chunks, 16 by default, keep line lengths that drift through
+/// the file (sorted input, say) from skewing the estimate.  One chunk samples
+/// just the start of the file.
+fn estimate_lines(mut inp: &std::fs::File, delimiter: u8, chunks: u64) -> io::Result<u64> {
+    let size = inp.metadata()?.len();
+
+    if size == 0 {
+        return Ok(0);
+    }
+
+    let (chunks, chunk_size) = if size <= ESTIMATE_LIMIT {
+        (1, size)
+    } else {
+        (chunks, ESTIMATE_LIMIT / chunks)
+    };
+
+    let mut buffer: Vec<u8> = vec![0; chunk_size as usize];
+    let mut newlines: u64 = 0;
+
+    // With several chunks the last always ends at the end of the file.
+    for i in 0..chunks {
+        let offset = match chunks {
+            1 => 0,
+            _ => i * (size - chunk_size) / (chunks - 1),
+        };
+        inp.seek(SeekFrom::Start(offset))?;
+        inp.read_exact(&mut buffer)?;
+        newlines += bytecount::count(&buffer, delimiter) as u64;
+    }
+
+    // Count a final line without a newline.
+    let mut last = [0; 1];
+    inp.seek(SeekFrom::End(-1))?;
+    inp.read_exact(&mut last)?;
+    inp.seek(SeekFrom::Start(0))?;
+    let trailing = if last[0] != delimiter { 1 } else { 0 };
+
+    if size <= ESTIMATE_LIMIT {
+        return Ok(newlines + trailing);
+    }
+
+    let sampled = chunks * chunk_size;
+    let estimate = (newlines as f64 * (size as f64 / sampled as f64)) as u64;
+
+    Ok(std::cmp::max(newlines, estimate) + trailing)
+}
+
+fn u64_from_hex(src: &[u8]) -> Option<u64> {
+    let mut result: u64 = 0;
+
+    for &c in src {
+        result = match result.checked_mul(16).and_then(|r| {
+            (c as char)
+                .to_digit(16)
+                .and_then(|x| r.checked_add(u64::from(x)))
+        }) {
+            Some(result) => result,
+            None => return None,
+        }
+    }
+
+    Some(result)
+}
+
+fn as_millis(d: Duration) -> f64 {
+    (d.as_secs() as f64) * 1000.0 + (f64::from(d.subsec_nanos()) / 1_000_000.0)
+}
+
+/// Nearest-rank percentile of an already sorted, non-empty slice.
+fn percentile(sorted: &[Duration], pct: f64) -> Duration {
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+fn open_gcs<P: AsRef<Path>>(filename: P) -> io::Result<GCSReader<BufReader<File>>> {
+    let file = File::open(filename)?;
+    let file = BufReader::new(file);
+    let mut searcher = GCSReader::new(file);
+    searcher.initialize()?;
+
+    Ok(searcher)
+}
+
+// Larger databases are better left to the page cache.
+const IN_MEMORY_LIMIT: u64 = 1024 * 1024 * 1024 * 4;
+
+/// Like `open_gcs`, but read the whole file into memory first so queries
+/// never touch the filesystem.
+fn open_gcs_in_memory<P: AsRef<Path>>(filename: P) -> io::Result<GCSReader<Cursor<Vec<u8>>>> {
+    let mut file = File::open(filename)?;
+    let size = file.metadata()?.len();
+    if size > IN_MEMORY_LIMIT {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "database is {} bytes, too large to load into memory (limit {})",
+                size, IN_MEMORY_LIMIT
+            ),
+        ));
+    }
+
+    let mut data = Vec::with_capacity(size as usize);
+    file.read_to_end(&mut data)?;
+    let mut searcher = GCSReader::new(Cursor::new(data));
+    searcher.initialize()?;
+
+    Ok(searcher)
+}
+
+/// Open a database on a web server, fetching only the parts queries need.
+fn open_gcs_http(url: &str) -> io::Result<GCSReader<BufReader<HttpReader>>> {
+    let mut searcher = GCSReader::new(BufReader::new(HttpReader::new(url)?));
+    searcher.initialize()?;
+
+    Ok(searcher)
+}
+
+struct QueryOptions {
+    max_steps: Option<u64>,
+    warm: bool,
+    prompt: String,
+    banner: bool,
+    /// No banner, prompt or timings, and buffered output, for piped use.
+    batch: bool,
+    /// Remember this many recent results by hash.
+    cache: Option<NonZeroUsize>,
+    /// Summarise query latency when input ends.
+    stats: bool,
+    /// Read raw 8-byte values and answer with a byte each.
+    binary: bool,
+    /// Load the whole database into memory.
+    in_memory: bool,
+    /// Report progress on stderr every this many batch queries.
+    progress: Option<u64>,
+    /// Colour interactive results.
+    color: bool,
+}
+
+/// Periodic progress through a long batch of queries, on stderr so stdout
+/// stays clean for the results.
+struct QueryProgress {
+    every: u64,
+    queries: u64,
+    found: u64,
+    start: Instant,
+}
+
+impl QueryProgress {
+    fn new(every: u64) -> Self {
+        Self {
+            every,
+            queries: 0,
+            found: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, found: bool) {
+        self.queries += 1;
+        if found {
+            self.found += 1;
+        }
+
+        if self.queries % self.every == 0 {
+            let secs = self.start.elapsed().as_secs_f64();
+            eprintln!(
+                "Queried {}, {} found ({:.2}%), {:.0}/sec",
+                self.queries,
+                self.found,
+                (self.found as f64 / self.queries as f64) * 100.0,
+                if secs > 0.0 {
+                    self.queries as f64 / secs
                 } else {
-                    u64_from_hex(&s[0..16])
+                    0.0
                 }
+            );
+        }
+    }
+}
+
+/// Answer a stream of raw 8-byte values, read in `endian` order, with one
+/// byte each: 1 if found, 0 if not, or 255 if --max-steps gave up.
+fn query_binary<R: Read + io::Seek>(
+    searcher: &mut GCSReader<R>,
+    endian: Endian,
+    max_steps: Option<u64>,
+    progress: Option<u64>,
+) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut input = BufReader::with_capacity(1024 * 64, stdin.lock());
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut progress = progress.map(QueryProgress::new);
+
+    let mut record = [0; 8];
+    loop {
+        // A short read at the very end is a truncated record, not EOF.
+        let mut len = 0;
+        while len < record.len() {
+            match input.read(&mut record[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        match len {
+            0 => break,
+            8 => (),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "input ends with a partial 8-byte record",
+                ))
             }
-            HashType::Md5 => Cursor::new(md5::Md5::digest(&s).as_slice())
-                .read_u64::<BigEndian>()
-                .ok(),
-            HashType::Sha1 => Cursor::new(sha1::Sha1::digest(&s).as_slice())
-                .read_u64::<BigEndian>()
-                .ok(),
-            HashType::Sha2_256 => Cursor::new(sha2::Sha256::digest(&s).as_slice())
-                .read_u64::<BigEndian>()
-                .ok(),
-            HashType::Sha2_512 => Cursor::new(sha2::Sha512::digest(&s).as_slice())
-                .read_u64::<BigEndian>()
-                .ok(),
-            HashType::Blake2b => Cursor::new(blake2::Blake2b::digest(&s).as_slice())
-                .read_u64::<BigEndian>()
-                .ok(),
         }
+
+        let val = match endian {
+            Endian::Big => BigEndian::read_u64(&record),
+            Endian::Little => LittleEndian::read_u64(&record),
+        };
+        let result = searcher.lookup_bounded(val, max_steps)?.0;
+        if let Some(ref mut progress) = progress {
+            progress.record(result == Lookup::Present);
+        }
+        let reply = match result {
+            Lookup::Present => serve::REPLY_PRESENT,
+            Lookup::Absent => serve::REPLY_ABSENT,
+            Lookup::Indeterminate => serve::REPLY_ERROR,
+        };
+        out.write_all(&[reply])?;
     }
+
+    out.flush()
 }
 
-const ESTIMATE_LIMIT: u64 = 1024 * 1024 * 16;
+/// Answer queries against a double-hashed container, which only supports
+/// plain lookups.
+fn query_double(searcher: &mut DoubleReader, key: &KeySpec, opts: &QueryOptions) -> io::Result<()> {
+    let key = &key.for_database(searcher.first.digest_order())?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let stdin = io::stdin();
 
-fn estimate_lines(mut inp: &std::fs::File) -> io::Result<u64> {
-    let size = inp.metadata()?.len();
-    let sample_size = std::cmp::min(size, ESTIMATE_LIMIT) as usize;
+    let interactive = !opts.batch;
+
+    if interactive && opts.banner {
+        writeln!(
+            out,
+            "Ready for queries on {} items, double-hashed at 1 in {} and 1 in {}.  ^D to exit.",
+            searcher.first.n, searcher.first.p, searcher.second.p
+        )?;
+    }
+
+    for record in stdin.lock().split(key.record_end()) {
+        let record = record?;
+        let line = key.trim_record(&record);
+
+        match key.digest_pair(line) {
+            Ok((first, second)) if searcher.exists(first, second)? => writeln!(out, "Found")?,
+            Ok(_) => writeln!(out, "Not found")?,
+            Err(e) => eprintln!("Error parsing '{}': {}", String::from_utf8_lossy(line), e),
+        }
+
+        if interactive {
+            out.flush()?;
+        }
+    }
+
+    out.flush()
+}
+
+/// Ask whether each key is in any of several databases, opening each the
+/// first time a query reaches it.  Stops at the first database with the key
+/// unless `all` is set.
+fn query_federated(
+    filenames: &[&OsStr],
+    key: &KeySpec,
+    opts: &QueryOptions,
+    all: bool,
+) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let stdin = io::stdin();
+
+    let interactive = !opts.batch;
+    // Each database with the key spec it records.
+    let mut searchers: Vec<Option<(GCSReader<BufReader<File>>, KeySpec)>> =
+        filenames.iter().map(|_| None).collect();
+
+    if interactive && opts.banner {
+        writeln!(
+            out,
+            "Ready for queries on {} databases.  ^D to exit.",
+            filenames.len()
+        )?;
+    }
+
+    for record in stdin.lock().split(key.record_end()) {
+        let record = record?;
+        let line = key.trim_record(&record);
+
+        // Databases may truncate digests differently, so hash once for each
+        // byte order as it's needed.
+        let mut vals: Vec<(Endian, u64)> = Vec::with_capacity(1);
+        let mut hits = Vec::new();
+        let mut failed = false;
+        for (i, filename) in filenames.iter().enumerate() {
+            if searchers[i].is_none() {
+                let mut searcher = open_gcs(filename)?;
+                if opts.warm {
+                    searcher.warm()?;
+                }
+                let db_key = key.for_database(searcher.digest_order())?;
+                searchers[i] = Some((searcher, db_key));
+            }
+
+            let (ref mut searcher, ref db_key) = *searchers[i].as_mut().unwrap();
+            let order = db_key.digest_order();
+            let val = match vals.iter().find(|&&(o, _)| o == order) {
+                Some(&(_, val)) => val,
+                None => match db_key.digest(line) {
+                    Ok(val) => {
+                        vals.push((order, val));
+                        val
+                    }
+                    Err(e) => {
+                        eprintln!("Error parsing '{}': {}", String::from_utf8_lossy(line), e);
+                        failed = true;
+                        break;
+                    }
+                },
+            };
+
+            if searcher.exists(val)? {
+                hits.push(Path::new(filename).display().to_string());
+                if !all {
+                    break;
+                }
+            }
+        }
+
+        if failed {
+            continue;
+        }
+        if hits.is_empty() {
+            writeln!(out, "Not found")?;
+        } else {
+            writeln!(out, "Found in {}", hits.join(" "))?;
+        }
+
+        if interactive {
+            out.flush()?;
+        }
+    }
+
+    out.flush()
+}
+
+fn query_gcs<P: AsRef<Path>>(filename: P, key: &KeySpec, opts: &QueryOptions) -> io::Result<()> {
+    if let Some(mut searcher) = DoubleReader::open(&filename)? {
+        return query_double(&mut searcher, key, opts);
+    }
+
+    if opts.in_memory {
+        query_single(key, opts, || open_gcs_in_memory(&filename))
+    } else {
+        query_single(key, opts, || open_gcs(&filename))
+    }
+}
+
+fn query_url(url: &str, key: &KeySpec, opts: &QueryOptions) -> io::Result<()> {
+    query_single(key, opts, || open_gcs_http(url))
+}
+
+/// Query a single database, opened (and reopened on :reload) with `open`.
+fn query_single<R, F>(key: &KeySpec, opts: &QueryOptions, open: F) -> io::Result<()>
+where
+    R: Read + io::Seek,
+    F: Fn() -> io::Result<GCSReader<R>>,
+{
+    let mut searcher = open()?;
+
+    if opts.warm {
+        let start = Instant::now();
+        let bytes = searcher.warm()?;
+        eprintln!(
+            "Warmed {} bytes in {:.1}ms",
+            bytes,
+            as_millis(start.elapsed())
+        );
+    }
+
+    if opts.binary {
+        // Raw values are already hashed, so only --endian says how to read them.
+        return query_binary(
+            &mut searcher,
+            key.digest_order(),
+            opts.max_steps,
+            opts.progress,
+        );
+    }
+    let mut db_key = key.for_database(searcher.digest_order())?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let stdin = io::stdin();
+
+    let interactive = !opts.batch;
+
+    // A given file always answers the same way for a hash, false positives
+    // included, so results are safe to reuse until the next :reload.
+    let mut cache = opts.cache.map(LruCache::new);
+    let mut hits: u64 = 0;
+    let mut queries: u64 = 0;
+    let mut timings: Vec<Duration> = Vec::new();
+    let mut progress = opts
+        .progress
+        .filter(|_| !interactive)
+        .map(QueryProgress::new);
+
+    if interactive && opts.banner {
+        writeln!(
+            out,
+            "Ready for queries on {} items with a 1 in {} false-positive rate.  ^D to exit.",
+            searcher.n, searcher.p
+        )?;
+        writeln!(out, "Type :reload to reopen the database.")?;
+    }
+    if interactive {
+        write!(out, "{}", opts.prompt)?;
+        out.flush()?;
+    }
+
+    for record in stdin.lock().split(key.record_end()) {
+        let record = record?;
+        let line = key.trim_record(&record);
+
+        if line == b":reload" {
+            // Keep the old reader if the new file is unreadable, e.g. mid-write.
+            let reopened = open().and_then(|reloaded| {
+                let reloaded_key = key.for_database(reloaded.digest_order())?;
+                Ok((reloaded, reloaded_key))
+            });
+            match reopened {
+                Ok((reloaded, reloaded_key)) => {
+                    searcher = reloaded;
+                    db_key = reloaded_key;
+                    if let Some(ref mut cache) = cache {
+                        cache.clear();
+                    }
+                    eprintln!(
+                        "Reloaded {} items with a 1 in {} false-positive rate.",
+                        searcher.n, searcher.p
+                    );
+                }
+                Err(e) => eprintln!("Reload failed, keeping existing database: {}", e),
+            }
+        } else {
+            match db_key.digest(line) {
+                Ok(val) => {
+                    let start = Instant::now();
+                    queries += 1;
+                    let cached = cache.as_mut().and_then(|c| c.get(&val).cloned());
+                    let (result, payload) = match cached {
+                        Some(result) => {
+                            hits += 1;
+                            result
+                        }
+                        None => {
+                            let result = searcher.lookup_bounded(val, opts.max_steps)?;
+                            // Giving up depends on --max-steps, not the file.
+                            if result.0 != Lookup::Indeterminate {
+                                if let Some(ref mut cache) = cache {
+                                    cache.put(val, result);
+                                }
+                            }
+                            result
+                        }
+                    };
+                    let elapsed = start.elapsed();
+                    if opts.stats {
+                        timings.push(elapsed);
+                    }
+                    if let Some(ref mut progress) = progress {
+                        progress.record(result == Lookup::Present);
+                    }
+                    let text = match (result, payload) {
+                        (Lookup::Present, Some(payload)) => format!("Found {}", payload),
+                        (Lookup::Present, None) => "Found".to_string(),
+                        (Lookup::Absent, _) => "Not found".to_string(),
+                        (Lookup::Indeterminate, _) => "Gave up".to_string(),
+                    };
+
+                    if interactive {
+                        // Pad before colouring, which adds invisible escape codes.
+                        let text = format!("{:<9}", text);
+                        let text = if opts.color {
+                            match result {
+                                Lookup::Present => text.green().to_string(),
+                                Lookup::Absent => text.red().to_string(),
+                                Lookup::Indeterminate => text.yellow().to_string(),
+                            }
+                        } else {
+                            text
+                        };
+                        writeln!(out, "{} in {:.1}ms", text, as_millis(elapsed))?;
+                    } else {
+                        writeln!(out, "{}", text)?;
+                    }
+                }
+                Err(e) => eprintln!("Error parsing '{}': {}", String::from_utf8_lossy(line), e),
+            }
+        }
+
+        if interactive {
+            write!(out, "{}", opts.prompt)?;
+            out.flush()?;
+        }
+    }
+    if interactive && opts.banner {
+        writeln!(out, "Exit")?;
+    }
+    if !timings.is_empty() {
+        timings.sort_unstable();
+        let total: f64 = timings.iter().cloned().map(as_millis).sum();
+        eprintln!(
+            "Queries: {} in {:.3}ms, {:.0}/sec",
+            timings.len(),
+            total,
+            timings.len() as f64 / (total / 1000.0)
+        );
+        eprintln!(
+            "Query latency: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            as_millis(percentile(&timings, 50.0)),
+            as_millis(percentile(&timings, 90.0)),
+            as_millis(percentile(&timings, 99.0)),
+            as_millis(timings[timings.len() - 1])
+        );
+    }
+    if cache.is_some() && queries > 0 {
+        eprintln!(
+            "Cache hits: {} of {} queries ({:.1}%)",
+            hits,
+            queries,
+            (hits as f64 / queries as f64) * 100.0
+        );
+    }
+
+    out.flush()
+}
+
+fn bench_gcs<P: AsRef<Path>>(filename: P, samples: u64, batch: bool) -> io::Result<()> {
+    let mut searcher = open_gcs(filename)?;
+
+    println!(
+        "Querying {} random values against {} items with a 1 in {} false-positive rate.",
+        samples, searcher.n, searcher.p
+    );
+
+    // Random 64-bit values are almost certainly not members, so any hit is
+    // a false positive.
+    let mut rng = rand::thread_rng();
+    let values: Vec<u64> = (0..samples).map(|_| rng.gen::<u64>()).collect();
+    let mut timings: Vec<Duration> = Vec::with_capacity(samples as usize);
+    let mut found: u64 = 0;
+    let (mut seeks, mut steps, mut bits) = (0, 0, 0);
+    let mut steps_sq: f64 = 0.0;
+
+    for &val in &values {
+        let start = Instant::now();
+        if searcher.exists(val)? {
+            found += 1;
+        }
+        timings.push(start.elapsed());
+
+        let cost = searcher.last_query_stats();
+        seeks += cost.seeks;
+        steps += cost.steps;
+        steps_sq += (cost.steps * cost.steps) as f64;
+        bits += cost.bits;
+    }
+
+    timings.sort_unstable();
+    let total: f64 = timings.iter().cloned().map(as_millis).sum();
+
+    println!(
+        "False positives: {} of {}, observed rate {:.9}, expected {:.9}",
+        found,
+        samples,
+        found as f64 / samples as f64,
+        1.0 / searcher.p as f64
+    );
+    println!(
+        "Query latency: mean {:.3}ms, median {:.3}ms",
+        total / samples as f64,
+        as_millis(timings[timings.len() / 2])
+    );
+    let mean_steps = steps as f64 / samples as f64;
+    println!(
+        "Query cost: mean {:.2} seeks, {:.1} steps (sd {:.1}), {:.1} bits",
+        seeks as f64 / samples as f64,
+        mean_steps,
+        (steps_sq / samples as f64 - mean_steps * mean_steps)
+            .max(0.0)
+            .sqrt(),
+        bits as f64 / samples as f64
+    );
+
+    if batch {
+        let start = Instant::now();
+        let batch_found = searcher
+            .exists_batch(&values)?
+            .into_iter()
+            .filter(|&f| f)
+            .count();
+        println!(
+            "Batch query: {} found in {:.3}ms total, versus {:.3}ms individually",
+            batch_found,
+            as_millis(start.elapsed()),
+            total
+        );
+    }
+
+    Ok(())
+}
+
+/// Build a database of `n` random values at 1-in-`p` in a temporary file,
+/// then check every member is found, batch and single lookups agree, and
+/// random non-members come up at about the expected false-positive rate.
+/// Returns whether all of that held.
+fn selftest(n: u64, p: u64) -> io::Result<bool> {
+    let path = std::env::temp_dir().join(format!("gcstool-selftest-{}.gcs", std::process::id()));
+    let result = run_selftest(&path, n, p);
+    let _ = fs::remove_file(&path);
+    result
+}
+
+fn run_selftest(path: &Path, n: u64, p: u64) -> io::Result<bool> {
+    let mut rng = rand::thread_rng();
+    let members: Vec<u64> = (0..n).map(|_| rng.gen::<u64>()).collect();
+
+    let mut status = Status::new(1);
+    let outfile = OpenOptions::new().write(true).create_new(true).open(path)?;
+    let mut gcs = GCSBuilder::new(BufWriter::new(outfile), n, p, 1024)?;
+    for &v in &members {
+        gcs.add(v);
+    }
+    gcs.finish(&mut status)?.flush()?;
+    status.done();
+
+    let mut searcher = open_gcs(path)?;
+    let mut ok = true;
+
+    let missing = members
+        .iter()
+        .filter(|&&v| !searcher.exists(v).unwrap_or(false))
+        .count();
+    println!("Members found: {} of {}", n - missing as u64, n);
+    ok &= missing == 0;
+
+    let batch_missing = searcher
+        .exists_batch(&members)?
+        .into_iter()
+        .filter(|&f| !f)
+        .count();
+    println!(
+        "Members found by batch query: {} of {}",
+        n - batch_missing as u64,
+        n
+    );
+    ok &= batch_missing == 0;
+
+    // Index points are matched by the index alone, and other members found by
+    // scanning the data.
+    let np = searcher.n * searcher.p;
+    let points: Vec<u64> = searcher.index_entries().iter().map(|&(v, _)| v).collect();
+    let mut wrong_source = 0;
+    for &v in &members {
+        let source = if points.binary_search(&(v % np)).is_ok() {
+            HitSource::Index
+        } else {
+            HitSource::Scan
+        };
+        if searcher.exists_detailed(v)? != Existence::Present(source) {
+            wrong_source += 1;
+        }
+    }
+    println!(
+        "Hit sources reported correctly: {} of {} ({} index points)",
+        n - wrong_source,
+        n,
+        points.len()
+    );
+    ok &= wrong_source == 0;
+
+    // Random values are almost certainly not members, so hits are false
+    // positives.  Members colliding mod n*p cover slightly less than 1 in p
+    // of the range.  Allow six standard deviations of slack either way.
+    let found = (0..n)
+        .filter(|_| searcher.exists(rng.gen::<u64>()).unwrap_or(false))
+        .count() as f64;
+    let rate = 1.0 - (-1.0 / searcher.p as f64).exp();
+    let expected = n as f64 * rate;
+    let slack = 6.0 * (expected * (1.0 - rate)).sqrt() + 1.0;
+    println!(
+        "False positives: {} of {}, observed rate {:.6}, expected {:.6}",
+        found,
+        n,
+        found / n as f64,
+        rate
+    );
+    ok &= (found - expected).abs() <= slack;
+
+    println!("Self-test {}", if ok { "passed" } else { "FAILED" });
+    Ok(ok)
+}
+
+/// FILE naming stdin rather than a file.
+const STDIN_INPUT: &str = "-";
+
+fn range_gcs<P: AsRef<Path>>(filename: P, from: u64, to: u64) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    if filename.as_ref() == Path::new(STDIN_INPUT) {
+        // A pipe can't seek to the footer, so decode from the header.
+        let stdin = io::stdin();
+        let mut searcher = GCSReader::new(stdin.lock());
+        searcher.initialize_streaming()?;
+        searcher.for_each_streamed(from, to, |v| writeln!(out, "{}", v))?;
+    } else {
+        let mut searcher = open_gcs(filename)?;
+        searcher.for_each_in_range(from, to, |v| writeln!(out, "{}", v))?;
+    }
+    out.flush()
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Every member of a database, reduced mod `basis` if that's below its n*p.
+fn members_mod<P: AsRef<Path>>(filename: P, basis: u64) -> io::Result<Vec<u64>> {
+    let mut searcher = open_gcs(filename)?;
+    let np = searcher.n * searcher.p;
+
+    let mut members = Vec::with_capacity(searcher.n as usize);
+    searcher.for_each_in_range(0, np, |v| {
+        members.push(v % basis);
+        Ok(())
+    })?;
+
+    // Reducing further can reorder and merge values.
+    if basis < np {
+        members.sort_unstable();
+        members.dedup();
+    }
+
+    Ok(members)
+}
+
+/// Report how many members two databases share, and how many each has alone.
+///
+/// Members are hashes reduced mod each database's n*p.  A hash reduced mod
+/// n*p can still be reduced mod any divisor of it, so both are compared mod
+/// the greatest common divisor of their n*p.  When that's smaller than
+/// either, distinct hashes can fall together and count as shared.
+fn compare_gcs<P: AsRef<Path>>(first: P, second: P) -> io::Result<()> {
+    let np = |filename: &P| open_gcs(filename).map(|s| s.n * s.p);
+    let (np_a, np_b) = (np(&first)?, np(&second)?);
+    let basis = gcd(np_a, np_b);
+    if basis != np_a || basis != np_b {
+        eprintln!(
+            "Warning: the databases reduce hashes mod different n*p ({} and {}), so they're compared mod {}: hashes that differ only above that look the same, overstating the overlap.",
+            np_a, np_b, basis
+        );
+    }
+
+    let a = members_mod(first, basis)?;
+    let b = members_mod(second, basis)?;
+
+    let mut shared: u64 = 0;
+    let mut rest = &b[..];
+    for v in &a {
+        // Both are sorted, so skip what can't match any more.
+        let skip = rest.iter().take_while(|&w| w < v).count();
+        rest = &rest[skip..];
+        if rest.first() == Some(v) {
+            shared += 1;
+            rest = &rest[1..];
+        }
+    }
+
+    let (len_a, len_b) = (a.len() as u64, b.len() as u64);
+    let union = len_a + len_b - shared;
+    println!("Compared mod {}", basis);
+    println!("First: {} values, second: {} values", len_a, len_b);
+    println!("Shared: {}", shared);
+    println!("Only in first: {}", len_a - shared);
+    println!("Only in second: {}", len_b - shared);
+    println!(
+        "Jaccard similarity: {:.4}",
+        if union > 0 {
+            shared as f64 / union as f64
+        } else {
+            0.0
+        }
+    );
+
+    Ok(())
+}
+
+/// Describe how a database was built, from its footer and index.
+fn info_gcs<P: AsRef<Path>>(filename: P) -> io::Result<()> {
+    let params = open_gcs(filename)?.params();
+
+    let items = match params.shard {
+        Some(shard) => {
+            println!(
+                "Items: {}, a shard of {} holding values from {}",
+                shard.count, params.n, shard.base
+            );
+            shard.count
+        }
+        None => {
+            println!("Items: {}", params.n);
+            params.n
+        }
+    };
+    println!("False positive rate: 1 in {}", params.p);
+    println!(
+        "Format version: {}, {} byte order",
+        params.version,
+        match params.byte_order {
+            Endian::Big => "big-endian",
+            Endian::Little => "little-endian",
+        }
+    );
+    println!(
+        "Keys: digests truncated {}",
+        match params.digest_order {
+            Endian::Big => "big-endian",
+            Endian::Little => "little-endian",
+        }
+    );
+    println!("Header: {}", if params.header { "yes" } else { "no" });
+    if params.index_len == 0 {
+        println!("Index: none");
+    } else {
+        println!(
+            "Index: {} entries, about 1 per {} items",
+            params.index_len, params.index_granularity
+        );
+    }
+    match params.payload_width {
+        Some(width) => println!("Payloads: {} bytes each", width),
+        None => println!("Payloads: none"),
+    }
+    println!("Data: {} bytes", params.data_bytes);
+    if items > 0 {
+        println!(
+            "{}",
+            gcs::item_bits_summary(params.data_bytes, items, params.p)
+        );
+    }
+
+    Ok(())
+}
+
+/// Print the index of a database, one (value, bit position) pair a line or
+/// as a JSON array.
+fn dump_index<P: AsRef<Path>>(filename: P, json: bool) -> io::Result<()> {
+    let searcher = open_gcs(filename)?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    let entries = searcher.index_entries();
+    if json {
+        writeln!(out, "[")?;
+        for (i, &(value, bit_pos)) in entries.iter().enumerate() {
+            let sep = if i + 1 < entries.len() { "," } else { "" };
+            writeln!(
+                out,
+                "  {{\"value\": {}, \"bit_pos\": {}}}{}",
+                value, bit_pos, sep
+            )?;
+        }
+        writeln!(out, "]")?;
+    } else {
+        for &(value, bit_pos) in entries {
+            writeln!(out, "{} {}", value, bit_pos)?;
+        }
+    }
+
+    out.flush()
+}
+
+/// Print the value each key hashes to, in hex and decimal, exactly as create
+/// and query compute it.  Keys come from the arguments, or else stdin.
+fn hash_keys(keys: Option<Vec<&str>>, key: &KeySpec) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    let mut print = |line: &[u8]| match key.digest(line) {
+        Ok(val) => writeln!(out, "{:016x} {}", val, val),
+        Err(e) => {
+            eprintln!("Error parsing '{}': {}", String::from_utf8_lossy(line), e);
+            Ok(())
+        }
+    };
+
+    match keys {
+        Some(keys) => {
+            for line in keys {
+                print(line.as_bytes())?;
+            }
+        }
+        None => {
+            let stdin = io::stdin();
+            for record in stdin.lock().split(key.record_end()) {
+                print(key.trim_record(&record?))?;
+            }
+        }
+    }
+
+    out.flush()
+}
+
+/// Split a database into `shards` files named `<prefix>.<k>.gcs`, each
+/// holding the members in the k'th equal slice of `[0, n*p)`.
+///
+/// Every shard keeps the original N and P in its footer, so it reduces a
+/// hash exactly as the original would: route a query for hash `h` to shard
+/// `(h % (n*p)) * shards / (n*p)` and ask it as usual.  Each encodes its
+/// first member from the bottom of its slice, recorded in a shard trailer,
+/// so together they're about the size of the original.
+fn split_gcs<P: AsRef<Path>>(
+    filename: P,
+    prefix: &str,
+    shards: u64,
+    index_gran: u64,
+) -> io::Result<()> {
+    let mut searcher = open_gcs(filename)?;
+    if searcher.has_payloads() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "splitting doesn't carry payloads over",
+        ));
+    }
+
+    let (n, p) = (searcher.n, searcher.p);
+    let np = n * p;
+    let bound = |k: u64| (u128::from(np) * u128::from(k) / u128::from(shards)) as u64;
+
+    let mut status = Status::new(1);
+
+    for k in 0..shards {
+        let out_filename = format!("{}.{}.gcs", prefix, k);
+        println!("Writing {}", out_filename);
+
+        let outfile = BufWriter::with_capacity(
+            1024 * 256,
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&out_filename)?,
+        );
+
+        let (from, to) = (bound(k), bound(k + 1));
+        let mut gcs = GCSBuilder::new(outfile, n / shards, p, index_gran)?;
+        gcs.set_modulus_n(n);
+        gcs.set_shard_base(from);
+        gcs.set_digest_order(searcher.digest_order());
+
+        searcher.for_each_in_range(from, to, |v| {
+            gcs.add_reduced(v);
+            Ok(())
+        })?;
+
+        gcs.finish(&mut status)?;
+    }
+    status.done();
+
+    Ok(())
+}
+
+/// Copy `filename` to `out_filename` with an index every `index_gran`
+/// entries.  The data region stays exactly as it was.
+fn reindex_gcs<P: AsRef<Path>>(filename: P, out_filename: P, index_gran: u64) -> io::Result<()> {
+    let mut searcher = open_gcs(filename)?;
+
+    let mut out = BufWriter::with_capacity(
+        1024 * 256,
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(out_filename)?,
+    );
+    let entries = searcher.reindex(&mut out, index_gran)?;
+    println!("Index: {} bytes ({} entries)", entries * 16, entries);
 
-    let mut buffer: Vec<u8> = vec![0; sample_size];
-    inp.read_exact(&mut buffer)?;
-    inp.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
 
-    let sample = Memchr::new(b'\n', &buffer).count() as u64;
+struct CreateOptions {
+    fp: u64,
+    index_gran: u64,
+    /// Index every this many bits of data instead of every `index_gran`
+    /// entries.
+    index_bits: Option<u64>,
+    /// Choose the granularity from the final count to meet this, for -i auto.
+    index_target: Option<gcs::IndexTarget>,
+    max_size: Option<u64>,
+    header: bool,
+    stats: bool,
+    max_skip: Option<f64>,
+    dry_run: bool,
+    mmap: bool,
+    byte_order: Endian,
+    /// Store a 1-based field of each line, split on the given delimiter, as
+    /// a payload of this many bytes.
+    payload: Option<(u8, usize, u8)>,
+    /// Build two tables from independent halves of each digest.
+    double: bool,
+    /// Bytes of output to buffer between writes.
+    output_buffer: usize,
+    /// Stop after adding this many values.
+    limit: Option<u64>,
+    /// Warn if building is expected to need more than this many bytes.
+    memory_warn: u64,
+    /// Pause after the memory warning so there's time to ^C.
+    pause: bool,
+    /// Suggest an index granularity, as none was given.
+    suggest_index: bool,
+    /// Chunks to sample when estimating the lines in a large input.
+    sample_chunks: u64,
+    /// Skip records shorter than this many bytes.
+    min_length: Option<usize>,
+    /// Skip records longer than this many bytes.
+    max_length: Option<usize>,
+}
 
-    Ok(sample * (size / (sample_size as u64)))
+impl CreateOptions {
+    /// Whether a trimmed record is within --min-length and --max-length.
+    fn length_ok(&self, record: &[u8]) -> bool {
+        self.min_length.map_or(true, |min| record.len() >= min)
+            && self.max_length.map_or(true, |max| record.len() <= max)
+    }
 }
 
-fn u64_from_hex(src: &[u8]) -> Option<u64> {
-    let mut result: u64 = 0;
+/// The false-positive rate create will use for `n` items, and the size it
+/// expects to end up with, header included.  None if --max-size can't be met.
+fn estimate_create(n: u64, opts: &CreateOptions) -> Option<(u64, GcsSizeEstimate, u64)> {
+    let p = match opts.max_size {
+        Some(max_size) => gcs::p_for_size(n, opts.index_gran, opts.header, max_size)?,
+        None => opts.fp,
+    };
 
-    for &c in src {
-        result = match result.checked_mul(16).and_then(|r| {
-            (c as char)
-                .to_digit(16)
-                .and_then(|x| r.checked_add(u64::from(x)))
-        }) {
-            Some(result) => result,
-            None => return None,
-        }
+    let index_gran = match (opts.index_bits, opts.index_target) {
+        (Some(bits), _) => gcs::granularity_for_bits(p, bits),
+        (None, Some(target)) => gcs::recommend_index_granularity(n, p, target),
+        (None, None) => opts.index_gran,
+    };
+    let estimate = gcs::estimate_gcs_size(n, p, index_gran);
+    let mut total = estimate.total();
+    if opts.header {
+        total += gcs::GCS_HEADER_LEN;
     }
 
-    Some(result)
+    Some((p, estimate, total))
 }
 
-fn query_gcs<P: AsRef<Path>>(filename: P, hash: &HashType) -> io::Result<()> {
-    let file = File::open(filename)?;
-    let file = BufReader::new(file);
-    let mut searcher = GCSReader::new(file);
-    searcher.initialize()?;
-
-    let mut stdout = io::stdout();
-    let stdin = io::stdin();
+fn dry_run_gcs(n: u64, opts: &CreateOptions) -> io::Result<()> {
+    let (p, estimate, total) = estimate_create(n, opts).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "no false-positive rate fits within --max-size",
+        )
+    })?;
 
+    println!("Estimated items: {}", n);
+    println!("False positive rate: 1 in {}", p);
     println!(
-        "Ready for queries on {} items with a 1 in {} false-positive rate.  ^D to exit.",
-        searcher.n, searcher.p
+        "Estimated memory use: {} MB",
+        estimate.memory / (1024 * 1024)
     );
-    print!("> ");
-    stdout.flush()?;
-
-    for line in stdin.lock().lines() {
-        let line = line?;
-
-        if let Some(val) = hash.digest(line.as_bytes()) {
-            let start = Instant::now();
-            let exists = searcher.exists(val).expect("Error in search");
-            let elapsed = start.elapsed();
-            println!(
-                "{} in {:.1}ms",
-                if exists { "Found" } else { "Not found" },
-                (elapsed.as_secs() as f64) * 1000.0
-                    + (f64::from(elapsed.subsec_nanos()) / 1_000_000.0)
-            );
-        } else {
-            eprintln!("Error parsing '{}'", line);
-        }
-        print!("> ");
-        stdout.flush()?;
-    }
-    println!("Exit");
-
+    println!("Estimated data size: {} bytes", estimate.data);
+    println!("Estimated index size: {} bytes", estimate.index);
+    println!("Estimated total size: {} bytes", total);
     Ok(())
 }
 
+const SUGGESTED_INDEX_FRACTION: f64 = 0.01;
+
+/// OUTPUT naming stdout rather than a file.
+const STDOUT_OUTPUT: &str = "-";
+
 fn create_gcs<P: AsRef<Path>>(
     in_filename: P,
     out_filename: P,
-    fp: u64,
-    index_gran: u64,
-    hash: &HashType,
+    opts: &CreateOptions,
+    key: &KeySpec,
 ) -> io::Result<()> {
+    let to_stdout = out_filename.as_ref() == Path::new(STDOUT_OUTPUT);
+    if to_stdout && (opts.double || opts.mmap) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--double and --mmap need an output file, not stdout",
+        ));
+    }
+
+    // Output is never overwritten today, but check anyway so the source
+    // stays safe if that changes.
+    if !to_stdout {
+        if let Ok(out_path) = fs::canonicalize(&out_filename) {
+            if out_path == fs::canonicalize(&in_filename)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "input and output are the same file",
+                ));
+            }
+        }
+    }
+
+    // Keep stdout clean for the database if that's where it's going.
+    let mut status = if to_stdout {
+        Status::with_sink(1, Box::new(PrintProgress::stderr()))
+    } else {
+        Status::new(1)
+    };
+
     let infile = File::open(in_filename)?;
-    let outfile = BufWriter::with_capacity(
-        1024 * 256,
-        OpenOptions::new()
+    let mut n = estimate_lines(&infile, key.record_end(), opts.sample_chunks)?;
+    if let Some(limit) = opts.limit {
+        n = std::cmp::min(n, limit);
+    }
+    if opts.suggest_index {
+        if let Some((p, _, _)) = estimate_create(n, opts) {
+            let target = gcs::IndexTarget::IndexFraction(SUGGESTED_INDEX_FRACTION);
+            // Zero means no index at all, which -i can't ask for.
+            let suggestion = match gcs::recommend_index_granularity(n, p, target) {
+                0 => format!("none, {} items are few enough to scan", n),
+                g => format!("-i {}", g),
+            };
+            status.message(&format!(
+                "Suggested index granularity for a {}% index: {}",
+                SUGGESTED_INDEX_FRACTION * 100.0,
+                suggestion
+            ));
+        }
+    }
+    if opts.dry_run {
+        return dry_run_gcs(n, opts);
+    }
+
+    let outfile = if to_stdout {
+        None
+    } else {
+        let out_path = out_filename.as_ref().to_path_buf();
+        let outfile = OpenOptions::new()
+            .read(opts.mmap)
             .write(true)
             .create_new(true)
-            .open(out_filename)?,
-    );
+            .open(&out_filename)?;
 
-    let n = estimate_lines(&infile)?;
-    println!(
+        // We created the output, so don't leave it half-written if interrupted.
+        ctrlc::set_handler(move || {
+            let _ = fs::remove_file(&out_path);
+            eprintln!("Interrupted, removed partial {}", out_path.display());
+            std::process::exit(130);
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        Some(outfile)
+    };
+
+    status.message(&format!(
         "Estimated memory use for {} items: {} MB.",
         n,
         (n * 8) / (1024 * 1024)
-    );
-    if n * 8 > 1024 * 1024 * 1024 * 2 {
-        println!("^C now and get a better computer if memory constrained");
-        thread::sleep(time::Duration::from_millis(4000));
+    ));
+    if n * 8 > opts.memory_warn {
+        status.message("^C now and get a better computer if memory constrained");
+        if opts.pause {
+            thread::sleep(time::Duration::from_millis(4000));
+        }
     }
 
-    let mut status = Status::new(1);
+    let outfile = match outfile {
+        Some(outfile) => outfile,
+        None => {
+            // The builder only ever writes forwards, index and footer after
+            // the data, so it never needs to seek.
+            let stdout: Box<dyn Write> = Box::new(io::stdout());
+            let out = BufWriter::with_capacity(opts.output_buffer, stdout);
+            build_gcs(infile, out, n, opts, key, status)?;
+            return Ok(());
+        }
+    };
+
+    let result = write_gcs(infile, outfile, n, opts, key, status);
+    if result.is_err() {
+        // Don't leave a partial database behind, where create_new would
+        // refuse to replace it on the next attempt.
+        let _ = fs::remove_file(&out_filename);
+    }
+    result
+}
 
-    let mut gcs = GCSBuilder::new(outfile, n, fp, index_gran).expect("Couldn't initialize builder");
+/// Build the database into a newly created output file.
+fn write_gcs(
+    infile: File,
+    outfile: File,
+    n: u64,
+    opts: &CreateOptions,
+    key: &KeySpec,
+    status: Status,
+) -> io::Result<()> {
+    if opts.double {
+        return build_double(infile, outfile, n, opts, key, status);
+    }
+
+    if opts.mmap {
+        let size = estimate_create(n, opts).map_or(0, |(_, _, total)| total);
+
+        let out = build_gcs(
+            infile,
+            MmapWriter::new(outfile, size)?,
+            n,
+            opts,
+            key,
+            status,
+        )?;
+        out.finish()?;
+    } else {
+        let out = BufWriter::with_capacity(opts.output_buffer, outfile);
+        build_gcs(infile, out, n, opts, key, status)?;
+    }
+
+    Ok(())
+}
+
+fn parse_payload(line: &[u8], delimiter: u8, field: usize, width: u8) -> Result<u64, &'static str> {
+    let field = line
+        .split(|b| *b == delimiter)
+        .nth(field - 1)
+        .ok_or("line has no payload field")?;
+
+    str::from_utf8(field)
+        .ok()
+        .and_then(|f| f.parse::<u64>().ok())
+        .filter(|&v| v <= gcs::payload_mask(width))
+        .ok_or("payload is not a number that fits in --payload-bytes")
+}
+
+fn build_gcs<W: Write>(
+    infile: File,
+    outfile: W,
+    n: u64,
+    opts: &CreateOptions,
+    key: &KeySpec,
+    mut status: Status,
+) -> io::Result<W> {
+    let mut gcs = GCSBuilder::new(outfile, n, opts.fp, opts.index_gran)?;
+    gcs.set_header(opts.header);
+    gcs.set_stats(opts.stats);
+    gcs.set_byte_order(opts.byte_order);
+    gcs.set_digest_order(key.digest_order());
+    if let Some(bits) = opts.index_bits {
+        gcs.set_index_bits(bits);
+    }
+    if let Some(target) = opts.index_target {
+        gcs.set_index_target(target);
+    }
+    if let Some((_, _, width)) = opts.payload {
+        gcs.set_payload_width(width)?;
+    }
+    if let Some(max_size) = opts.max_size {
+        gcs.set_max_size(max_size);
+    }
 
     // infile.lines(): 2.27 M/sec
     // infile.read_line(): 2.56 M/sec (by saving String allocation)
@@ -196,72 +1642,718 @@ fn create_gcs<P: AsRef<Path>>(
     // infile.take(128).read_until(): 2.7 M/sec
     // LineReader::next_line(): 3.8 M/sec
 
+    let mut lines: u64 = 0;
+    let mut skipped: u64 = 0;
+    let mut filtered: u64 = 0;
+    let mut skip_reason = "";
+
     status.stage_work("Hashing", n);
-    let mut reader = LineReader::new(infile);
+    let mut expected = n;
+    let mut reader = LineReader::with_delimiter_and_capacity(key.record_end(), 64 * 1024, infile);
     while let Some(line) = reader.next_line() {
-        let line = line?.split(|b| *b == b'\n' || *b == b'\r').next().unwrap();
-        if let Some(hash) = hash.digest(&line) {
-            gcs.add(hash);
+        let line = key.trim_record(line?);
+        lines += 1;
+        outgrow_estimate(&mut status, lines, &mut expected);
+        if !opts.length_ok(line) {
+            filtered += 1;
+            continue;
+        }
+        let payload = match opts.payload {
+            Some((delimiter, field, width)) => {
+                parse_payload(line, delimiter, field, width).map(Some)
+            }
+            None => Ok(None),
+        };
+        match key
+            .digest(line)
+            .and_then(|hash| payload.map(|payload| (hash, payload)))
+        {
+            Ok((hash, Some(payload))) => {
+                gcs.add_with_payload(hash, payload);
 
-            status.incr();
-        } else {
-            eprintln!("Skipping line: {:?}", line);
+                status.incr();
+            }
+            Ok((hash, None)) => {
+                gcs.add(hash);
+
+                status.incr();
+            }
+            Err(e) => {
+                skipped += 1;
+                skip_reason = e;
+            }
+        }
+
+        if opts.limit == Some(lines - skipped - filtered) {
+            break;
+        }
+    }
+
+    check_skipped(lines, skipped, filtered, skip_reason, opts.max_skip)?;
+
+    let out = gcs.finish(&mut status)?;
+    status.done();
+
+    Ok(out)
+}
+
+/// The line count of a large input is only estimated, so once `lines` runs
+/// past the `expected` total, expect another tenth of it rather than let the
+/// progress run beyond 100%.
+fn outgrow_estimate(status: &mut Status, lines: u64, expected: &mut u64) {
+    if lines > *expected {
+        let more = std::cmp::max(*expected / 10, 1);
+        status.add_expected_work(more);
+        *expected += more;
+    }
+}
+
+/// Report lines that couldn't be hashed, failing if there were more than
+/// `max_skip` percent of them, and lines `filtered` out by length, which
+/// were left out on purpose and so don't count towards it.
+fn check_skipped(
+    lines: u64,
+    skipped: u64,
+    filtered: u64,
+    reason: &str,
+    max_skip: Option<f64>,
+) -> io::Result<()> {
+    if filtered > 0 {
+        eprintln!(
+            "Skipped {} of {} lines ({:.2}%) outside the length limits",
+            filtered,
+            lines,
+            (filtered as f64 / lines as f64) * 100.0
+        );
+    }
+
+    if skipped == 0 {
+        return Ok(());
+    }
+
+    let percent = (skipped as f64 / lines as f64) * 100.0;
+    eprintln!(
+        "Skipped {} of {} lines ({:.2}%) that could not be hashed, e.g. {}",
+        skipped, lines, percent, reason
+    );
+
+    if max_skip.map_or(false, |max| percent > max) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many lines skipped, is --hash right for this input?",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Build a double-hashed container: a table for each half of the digest,
+/// written one after the other.  See the double module for the details.
+fn build_double(
+    infile: File,
+    mut outfile: File,
+    n: u64,
+    opts: &CreateOptions,
+    key: &KeySpec,
+    mut status: Status,
+) -> io::Result<()> {
+    // The clone shares the file position, so the second table lands after
+    // the first.
+    let second_file = outfile.try_clone()?;
+    let mut tables = Vec::with_capacity(2);
+    for file in [outfile.try_clone()?, second_file] {
+        let mut gcs = GCSBuilder::new(
+            BufWriter::with_capacity(opts.output_buffer, file),
+            n,
+            opts.fp,
+            opts.index_gran,
+        )?;
+        gcs.set_header(opts.header);
+        gcs.set_stats(opts.stats);
+        gcs.set_byte_order(opts.byte_order);
+        gcs.set_digest_order(key.digest_order());
+        if let Some(bits) = opts.index_bits {
+            gcs.set_index_bits(bits);
+        }
+        if let Some(target) = opts.index_target {
+            gcs.set_index_target(target);
+        }
+        tables.push(gcs);
+    }
+
+    let mut lines: u64 = 0;
+    let mut skipped: u64 = 0;
+    let mut filtered: u64 = 0;
+    let mut skip_reason = "";
+
+    status.stage_work("Hashing", n);
+    let mut expected = n;
+    let mut reader = LineReader::with_delimiter_and_capacity(key.record_end(), 64 * 1024, infile);
+    while let Some(line) = reader.next_line() {
+        let line = key.trim_record(line?);
+        lines += 1;
+        outgrow_estimate(&mut status, lines, &mut expected);
+        if !opts.length_ok(line) {
+            filtered += 1;
+            continue;
+        }
+        match key.digest_pair(line) {
+            Ok((first, second)) => {
+                tables[0].add(first);
+                tables[1].add(second);
+
+                status.incr();
+            }
+            Err(e) => {
+                skipped += 1;
+                skip_reason = e;
+            }
+        }
+
+        if opts.limit == Some(lines - skipped - filtered) {
+            break;
         }
     }
 
-    gcs.finish(&mut status)?;
+    check_skipped(lines, skipped, filtered, skip_reason, opts.max_skip)?;
+
+    let mut lens = Vec::with_capacity(2);
+    let mut start = 0;
+    for gcs in tables {
+        gcs.finish(&mut status)?;
+        let end = outfile.stream_position()?;
+        lens.push(end - start);
+        start = end;
+    }
+
+    let mut out = BufWriter::new(outfile);
+    double::write_trailer(&mut out, lens[0], lens[1])?;
+    out.flush()?;
     status.done();
 
     Ok(())
 }
 
 fn main() {
+    let hash_names: Vec<&str> = HASHERS.iter().map(|&(name, _)| name).collect();
     let args = clap_app!(gcstool =>
         (@setting SubcommandRequiredElseHelp)
         (version: "0.1.0")
         (author: "Thomas Hurst <tom@hur.st>")
         (about: "Golomb Compressed Sets tool -- compact set membership database.")
         (@arg verbose: -v --verbose "Be verbose")
-        (@arg hash: -H --hash +takes_value possible_values(&["hex", "sha1", "sha256", "sha512", "md5", "blake2b"]) default_value("sha1") "Hash function")
+        (@arg hash: -H --hash +takes_value possible_values(&hash_names) default_value("sha1") "Hash function, or hex to take the first 16 hex digits of each key as-is")
+        (@arg endian: -E --endian +takes_value possible_values(&["be", "le"]) "Byte order of digest truncation [default: be].  Create records it, and queries follow the database unless this is given.")
+        (@arg field: -f --field +takes_value "Hash only this 1-based field of each line")
+        (@arg delimiter: -d --delimiter +takes_value default_value(",") "Field delimiter for --field")
+        (@arg normalize: -N --normalize +takes_value possible_values(&["ascii-lowercase", "lowercase"]) "Normalise keys before hashing, must match between create and query")
+        (@arg append_newline: --("append-newline") "Hash each key with a trailing newline, must match between create and query")
+        (@arg null: short("0") --null "Records end in NUL instead of newline, for input from find -print0 and friends")
         (@subcommand create =>
             (about: "Create GCS database from file")
             (@arg probability: -p +takes_value default_value("16777216") "False positive rate for queries, 1-in-p.")
-            (@arg index_granularity: -i +takes_value default_value("1024") "Entries per index point (16 bytes each).")
+            (@arg index_granularity: -i +takes_value default_value("1024") "Entries per index point (16 bytes each), or auto for an index about 1% the size of the data.")
+            (@arg index_by_bits: --("index-by-bits") +takes_value conflicts_with[index_granularity] "Place an index point every this many bits of data instead, evening out scan lengths.")
+            (@arg query_bits: --("query-bits") +takes_value conflicts_with[index_granularity index_by_bits] "Choose the index granularity so queries decode about this many bits of data on average.")
+            (@arg max_size: --("max-size") +takes_value "Choose the largest false-positive rate that fits in this many bytes, overriding -p.")
+            (@arg min_length: --("min-length") +takes_value "Skip lines shorter than this many bytes.")
+            (@arg max_length: --("max-length") +takes_value "Skip lines longer than this many bytes.")
+            (@arg max_skip: --("max-skip") +takes_value "Fail if more than this percentage of lines can't be hashed.")
+            (@arg stats: --stats "Print a histogram of Golomb quotients after encoding.")
+            (@arg header: --header "Also write N and P in a header at the start of the file.")
+            (@arg dry_run: --("dry-run") "Print estimated memory use and output size without building.")
+            (@arg mmap: --mmap "Write the database through a memory map instead of a buffer.")
+            (@arg payload_field: --("payload-field") +takes_value "Store this 1-based field of each line, a number, as the member's payload.")
+            (@arg payload_bytes: --("payload-bytes") +takes_value default_value("4") "Bytes per payload, 1 to 8.")
+            (@arg double: --double "Experimental: build two tables from independent halves of each digest, for a 1 in p*p false-positive rate.")
+            (@arg memory_warn_limit: --("memory-warn-limit") +takes_value default_value("2048") "Warn if building needs more than this many MB of memory.")
+            (@arg yes: -y --yes "Don't pause after the memory warning.")
+            (@arg sample_chunks: --("sample-chunks") +takes_value default_value("16") "Estimate the lines in inputs over 16MB from this many chunks spread across the file, or 1 for just its start.")
+            (@arg limit: --limit +takes_value "Stop after adding this many values, for quick test builds from large inputs.")
+            (@arg output_buffer_size: --("output-buffer-size") +takes_value default_value("256") "Output buffer size in KB.")
+            (@arg byte_order: --("byte-order") +takes_value possible_values(&["be", "le"]) default_value("be") "Byte order of the integers in the header, index and footer.")
             (@arg INPUT: +required "Input file")
-            (@arg OUTPUT: +required "Database to build")
+            (@arg OUTPUT: +required "Database to build, or - for stdout")
         )
         (@subcommand query =>
             (about: "Query a database")
+            (@arg prompt: --prompt +takes_value default_value("> ") "Prompt to print before each query.")
+            (@arg no_banner: --("no-banner") "Don't print the startup banner or exit message.")
+            (@arg batch: --batch "Print one bare result per line, the default unless on a terminal.")
+            (@arg warm: --warm "Read the database into the page cache before querying.")
+            (@arg in_memory: --("in-memory") "Load the whole database into memory before querying.")
+            (@arg max_steps: --("max-steps") +takes_value "Give up on a query after decoding this many values.")
+            (@arg cache: --cache +takes_value "Cache the results of this many recent queries.")
+            (@arg stats: --stats "Print latency percentiles and throughput when input ends.")
+            (@arg binary_input: --("binary-input") "Read raw 8-byte values in --endian order and write a byte per query: 1 found, 0 not, 255 gave up.")
+            (@arg no_color: --("no-color") "Don't colour interactive results.  NO_COLOR in the environment does the same.")
+            (@arg progress: --progress +takes_value "In batch mode, report progress on stderr every this many queries.")
+            (@arg all: --all "With several databases, list every one holding the key rather than the first.")
+            (@arg url: --url +takes_value conflicts_with[FILE in_memory] "Query a database on a web server that supports range requests, instead of a file.")
+            (@arg FILE: required_unless("url") ... "Databases to query, checked in turn")
+        )
+        (@subcommand range =>
+            (about: "List the members of a database within a range of values")
+            (@arg from: --from +takes_value default_value("0") "Lowest value to list.")
+            (@arg to: --to +takes_value "List values below this, defaulting to all.")
+            (@arg FILE: +required "Database to read, or - for stdin if it was built with --header")
+        )
+        (@subcommand split =>
+            (about: "Split a database into shards by value range")
+            (@arg shards: --shards +takes_value +required "Number of shards to write.")
+            (@arg index_granularity: -i +takes_value default_value("1024") "Entries per index point in each shard.")
+            (@arg FILE: +required "Database to split")
+            (@arg PREFIX: +required "Shards are written to PREFIX.0.gcs, PREFIX.1.gcs and so on")
+        )
+        (@subcommand reindex =>
+            (about: "Copy a database with a new index, without the original input")
+            (@arg index_granularity: -i --("index-granularity") +takes_value +required "Entries per index point in the copy.")
+            (@arg FILE: +required "Database to read")
+            (@arg OUTPUT: +required "Database to write")
+        )
+        (@subcommand compare =>
+            (about: "Report how many members two databases share")
+            (@arg FIRST: +required "First database")
+            (@arg SECOND: +required "Second database")
+        )
+        (@subcommand info =>
+            (about: "Describe how a database was built")
+            (@arg FILE: +required "Database to read")
+        )
+        (@subcommand dump_index =>
+            (name: "dump-index")
+            (about: "Print the index entries of a database")
+            (@arg json: --json "Print a JSON array instead of a value and bit position a line.")
+            (@arg FILE: +required "Database to read")
+        )
+        (@subcommand hash =>
+            (about: "Print the hash of each key, as create and query see it")
+            (@arg KEY: ... "Keys to hash, read from stdin if none are given")
+        )
+        (@subcommand serve =>
+            (about: "Serve membership queries over a socket")
+            (@arg listen: --listen +takes_value "TCP address to listen on, e.g. 127.0.0.1:4321")
+            (@arg unix: --unix +takes_value "Unix socket path to listen on")
+            (@arg FILE: +required "Database to serve")
+        )
+        (@subcommand selftest =>
+            (@setting Hidden)
+            (about: "Build, query and verify a random database, to check this build works")
+            (@arg count: -n +takes_value default_value("100000") "Number of random members.")
+            (@arg probability: -p +takes_value default_value("1024") "False positive rate, 1-in-p.")
+        )
+        (@subcommand bench =>
+            (about: "Measure false-positive rate and latency with random queries")
+            (@arg samples: -k +takes_value default_value("100000") "Number of random values to query.")
+            (@arg batch: --batch "Also time the same values as a single batch query.")
             (@arg FILE: +required "Database to query")
         )
     ).get_matches();
 
-    let hash = value_t!(args.value_of("hash"), HashType).unwrap_or_else(|e| e.exit());
+    let key = KeySpec {
+        hash: value_t!(args.value_of("hash"), HashType).unwrap_or_else(|e| e.exit()),
+        endian: if args.is_present("endian") {
+            Some(value_t!(args.value_of("endian"), Endian).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        },
+        field: if args.is_present("field") {
+            let field = value_t!(args.value_of("field"), usize).unwrap_or_else(|e| e.exit());
+            let delimiter = args.value_of("delimiter").unwrap().as_bytes();
+            if field == 0 || delimiter.len() != 1 {
+                clap::Error::value_validation_auto(
+                    "--field must be at least 1 and --delimiter a single byte".to_string(),
+                )
+                .exit();
+            }
+            Some((delimiter[0], field))
+        } else {
+            None
+        },
+        normalize: if args.is_present("normalize") {
+            Some(value_t!(args.value_of("normalize"), Normalize).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        },
+        append_newline: args.is_present("append_newline"),
+        null: args.is_present("null"),
+    };
 
     match args.subcommand() {
         ("create", Some(matches)) => {
             let in_filename = matches.value_of_os("INPUT").unwrap();
             let out_filename = matches.value_of_os("OUTPUT").unwrap();
 
-            let fp = value_t!(matches, "probability", u64).unwrap_or_else(|e| e.exit());
+            let opts = CreateOptions {
+                fp: value_t!(matches, "probability", u64).unwrap_or_else(|e| e.exit()),
+                // Chosen by the builder for -i auto and --query-bits.
+                index_gran: if matches.value_of("index_granularity") == Some("auto")
+                    || matches.is_present("query_bits")
+                {
+                    0
+                } else {
+                    value_t!(matches, "index_granularity", u64).unwrap_or_else(|e| e.exit())
+                },
+                index_target: if matches.is_present("query_bits") {
+                    let bits = value_t!(matches, "query_bits", u64).unwrap_or_else(|e| e.exit());
+                    if bits == 0 {
+                        clap::Error::value_validation_auto(
+                            "--query-bits must be greater than 0".to_string(),
+                        )
+                        .exit();
+                    }
+                    Some(gcs::IndexTarget::QueryBits(bits))
+                } else if matches.value_of("index_granularity") == Some("auto") {
+                    Some(gcs::IndexTarget::IndexFraction(SUGGESTED_INDEX_FRACTION))
+                } else {
+                    None
+                },
+                index_bits: if matches.is_present("index_by_bits") {
+                    let bits = value_t!(matches, "index_by_bits", u64).unwrap_or_else(|e| e.exit());
+                    if bits == 0 {
+                        clap::Error::value_validation_auto(
+                            "--index-by-bits must be greater than 0".to_string(),
+                        )
+                        .exit();
+                    }
+                    Some(bits)
+                } else {
+                    None
+                },
+                max_size: if matches.is_present("max_size") {
+                    Some(value_t!(matches, "max_size", u64).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+                header: matches.is_present("header"),
+                stats: matches.is_present("stats"),
+                max_skip: if matches.is_present("max_skip") {
+                    Some(value_t!(matches, "max_skip", f64).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+                dry_run: matches.is_present("dry_run"),
+                mmap: matches.is_present("mmap"),
+                byte_order: value_t!(matches, "byte_order", Endian).unwrap_or_else(|e| e.exit()),
+                payload: if matches.is_present("payload_field") {
+                    let field =
+                        value_t!(matches, "payload_field", usize).unwrap_or_else(|e| e.exit());
+                    let width = value_t!(matches, "payload_bytes", u8).unwrap_or_else(|e| e.exit());
+                    let delimiter = args.value_of("delimiter").unwrap().as_bytes();
+                    if field == 0 || width == 0 || width > 8 || delimiter.len() != 1 {
+                        clap::Error::value_validation_auto(
+                            "--payload-field must be at least 1, --payload-bytes 1 to 8 and --delimiter a single byte".to_string(),
+                        )
+                        .exit();
+                    }
+                    Some((delimiter[0], field, width))
+                } else {
+                    None
+                },
+                double: matches.is_present("double"),
+                output_buffer: {
+                    let kb =
+                        value_t!(matches, "output_buffer_size", usize).unwrap_or_else(|e| e.exit());
+                    if kb == 0 {
+                        clap::Error::value_validation_auto(
+                            "output buffer size must be greater than 0".to_string(),
+                        )
+                        .exit();
+                    }
+                    kb * 1024
+                },
+                limit: if matches.is_present("limit") {
+                    let limit = value_t!(matches, "limit", u64).unwrap_or_else(|e| e.exit());
+                    if limit == 0 {
+                        clap::Error::value_validation_auto(
+                            "--limit must be at least 1".to_string(),
+                        )
+                        .exit();
+                    }
+                    Some(limit)
+                } else {
+                    None
+                },
+                memory_warn: value_t!(matches, "memory_warn_limit", u64)
+                    .unwrap_or_else(|e| e.exit())
+                    .saturating_mul(1024 * 1024),
+                // Only someone at a terminal can act on the warning.
+                pause: !matches.is_present("yes") && io::stdin().is_terminal(),
+                suggest_index: matches.occurrences_of("index_granularity") == 0
+                    && !matches.is_present("index_by_bits")
+                    && !matches.is_present("query_bits"),
+                sample_chunks: {
+                    let chunks =
+                        value_t!(matches, "sample_chunks", u64).unwrap_or_else(|e| e.exit());
+                    if chunks == 0 || chunks > ESTIMATE_LIMIT {
+                        clap::Error::value_validation_auto(format!(
+                            "--sample-chunks must be 1 to {}",
+                            ESTIMATE_LIMIT
+                        ))
+                        .exit();
+                    }
+                    chunks
+                },
+                min_length: if matches.is_present("min_length") {
+                    Some(value_t!(matches, "min_length", usize).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+                max_length: if matches.is_present("max_length") {
+                    Some(value_t!(matches, "max_length", usize).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+            };
+
+            // --max-size picks p assuming a given granularity.
+            if opts.index_target.is_some() && opts.max_size.is_some() {
+                clap::Error::value_validation_auto(
+                    "-i auto and --query-bits can't be combined with --max-size".to_string(),
+                )
+                .exit();
+            }
+
+            if let (Some(min), Some(max)) = (opts.min_length, opts.max_length) {
+                if min > max {
+                    clap::Error::value_validation_auto(
+                        "--min-length can't be above --max-length".to_string(),
+                    )
+                    .exit();
+                }
+            }
+
+            if opts.double && (opts.mmap || opts.payload.is_some() || opts.max_size.is_some()) {
+                clap::Error::value_validation_auto(
+                    "--double can't be combined with --mmap, --payload-field or --max-size"
+                        .to_string(),
+                )
+                .exit();
+            }
+
+            if opts.index_bits.is_some() && (opts.payload.is_some() || opts.max_size.is_some()) {
+                clap::Error::value_validation_auto(
+                    "--index-by-bits can't be combined with --payload-field or --max-size"
+                        .to_string(),
+                )
+                .exit();
+            }
+
+            if let Err(e) = create_gcs(in_filename, out_filename, &opts, &key) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("query", Some(matches)) => {
+            let filenames: Vec<&OsStr> = matches
+                .values_of_os("FILE")
+                .map_or_else(Vec::new, |files| files.collect());
+
+            let opts = QueryOptions {
+                max_steps: if matches.is_present("max_steps") {
+                    Some(value_t!(matches, "max_steps", u64).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+                warm: matches.is_present("warm"),
+                prompt: matches.value_of("prompt").unwrap().to_owned(),
+                banner: !matches.is_present("no_banner"),
+                batch: matches.is_present("batch")
+                    || !io::stdin().is_terminal()
+                    || !io::stdout().is_terminal(),
+                cache: if matches.is_present("cache") {
+                    let size = value_t!(matches, "cache", usize).unwrap_or_else(|e| e.exit());
+                    Some(NonZeroUsize::new(size).unwrap_or_else(|| {
+                        clap::Error::value_validation_auto(
+                            "cache size must be greater than 0".to_string(),
+                        )
+                        .exit()
+                    }))
+                } else {
+                    None
+                },
+                stats: matches.is_present("stats"),
+                binary: matches.is_present("binary_input"),
+                in_memory: matches.is_present("in_memory"),
+                progress: if matches.is_present("progress") {
+                    let every = value_t!(matches, "progress", u64).unwrap_or_else(|e| e.exit());
+                    if every == 0 {
+                        clap::Error::value_validation_auto(
+                            "progress interval must be greater than 0".to_string(),
+                        )
+                        .exit();
+                    }
+                    Some(every)
+                } else {
+                    None
+                },
+                // Batch output is never coloured, so only the flag and a
+                // non-empty NO_COLOR need checking here.
+                color: !matches.is_present("no_color")
+                    && std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty()),
+            };
+
+            let result = if let Some(url) = matches.value_of("url") {
+                query_url(url, &key, &opts)
+            } else if filenames.len() > 1 {
+                if opts.binary
+                    || opts.cache.is_some()
+                    || opts.stats
+                    || opts.max_steps.is_some()
+                    || opts.in_memory
+                    || opts.progress.is_some()
+                {
+                    clap::Error::value_validation_auto(
+                        "--binary-input, --cache, --stats, --max-steps, --in-memory and --progress need a single database".to_string(),
+                    )
+                    .exit();
+                }
+                query_federated(&filenames, &key, &opts, matches.is_present("all"))
+            } else {
+                query_gcs(filenames[0], &key, &opts)
+            };
+
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("range", Some(matches)) => {
+            let filename = matches.value_of_os("FILE").unwrap();
+
+            let from = value_t!(matches, "from", u64).unwrap_or_else(|e| e.exit());
+            let to = if matches.is_present("to") {
+                value_t!(matches, "to", u64).unwrap_or_else(|e| e.exit())
+            } else {
+                u64::MAX
+            };
+
+            if let Err(e) = range_gcs(filename, from, to) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("split", Some(matches)) => {
+            let filename = matches.value_of_os("FILE").unwrap();
+            let prefix = matches.value_of("PREFIX").unwrap();
+
+            let shards = value_t!(matches, "shards", u64).unwrap_or_else(|e| e.exit());
+            if shards == 0 {
+                clap::Error::value_validation_auto("shards must be greater than 0".to_string())
+                    .exit();
+            }
             let index_gran =
                 value_t!(matches, "index_granularity", u64).unwrap_or_else(|e| e.exit());
 
-            if let Err(e) = create_gcs(in_filename, out_filename, fp, index_gran, &hash) {
+            if let Err(e) = split_gcs(filename, prefix, shards, index_gran) {
                 eprintln!("Error: {}", e);
 
                 std::process::exit(1);
             }
         }
-        ("query", Some(matches)) => {
+        ("reindex", Some(matches)) => {
+            let filename = matches.value_of_os("FILE").unwrap();
+            let out_filename = matches.value_of_os("OUTPUT").unwrap();
+            let index_gran =
+                value_t!(matches, "index_granularity", u64).unwrap_or_else(|e| e.exit());
+
+            if let Err(e) = reindex_gcs(filename, out_filename, index_gran) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("compare", Some(matches)) => {
+            let first = matches.value_of_os("FIRST").unwrap();
+            let second = matches.value_of_os("SECOND").unwrap();
+
+            if let Err(e) = compare_gcs(first, second) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("info", Some(matches)) => {
+            let filename = matches.value_of_os("FILE").unwrap();
+
+            if let Err(e) = info_gcs(filename) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("dump-index", Some(matches)) => {
+            let filename = matches.value_of_os("FILE").unwrap();
+
+            if let Err(e) = dump_index(filename, matches.is_present("json")) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("hash", Some(matches)) => {
+            let keys = matches.values_of("KEY").map(|keys| keys.collect());
+
+            if let Err(e) = hash_keys(keys, &key) {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("serve", Some(matches)) => {
             let filename = matches.value_of_os("FILE").unwrap();
 
-            if let Err(e) = query_gcs(filename, &hash) {
+            let result = match (matches.value_of("listen"), matches.value_of_os("unix")) {
+                (Some(addr), None) => serve::serve_tcp(filename, key, addr),
+                (None, Some(path)) => serve::serve_unix(filename, key, Path::new(path)),
+                _ => clap::Error::value_validation_auto(
+                    "exactly one of --listen or --unix is required".to_string(),
+                )
+                .exit(),
+            };
+
+            if let Err(e) = result {
+                eprintln!("Error: {}", e);
+
+                std::process::exit(1);
+            }
+        }
+        ("bench", Some(matches)) => {
+            let filename = matches.value_of_os("FILE").unwrap();
+
+            let samples = value_t!(matches, "samples", u64).unwrap_or_else(|e| e.exit());
+            if samples == 0 {
+                clap::Error::value_validation_auto("samples must be greater than 0".to_string())
+                    .exit();
+            }
+
+            if let Err(e) = bench_gcs(filename, samples, matches.is_present("batch")) {
                 eprintln!("Error: {}", e);
 
                 std::process::exit(1);
             }
         }
+        ("selftest", Some(matches)) => {
+            let n = value_t!(matches, "count", u64).unwrap_or_else(|e| e.exit());
+            let p = value_t!(matches, "probability", u64).unwrap_or_else(|e| e.exit());
+            if n == 0 || p == 0 {
+                clap::Error::value_validation_auto("-n and -p must be greater than 0".to_string())
+                    .exit();
+            }
+
+            match selftest(n, p) {
+                Ok(true) => (),
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+
+                    std::process::exit(1);
+                }
+            }
+        }
         _ => {
             unreachable!();
         }
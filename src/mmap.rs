@@ -0,0 +1,80 @@
+//! A `Write` implementation over a memory-mapped file, so `create --mmap` can
+//! hand encoded bytes straight to the page cache instead of copying them
+//! through a `BufWriter`.
+//!
+//! The final size isn't known until encoding completes, so the file is
+//! preallocated to an estimate, grown by remapping if the estimate was short,
+//! and truncated to the bytes actually written by `finish`.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use memmap2::MmapMut;
+
+pub struct MmapWriter {
+    file: File,
+    map: Option<MmapMut>,
+    len: u64,
+    pos: u64,
+}
+
+impl MmapWriter {
+    /// Map `file`, preallocated to `size_hint` bytes.
+    pub fn new(file: File, size_hint: u64) -> io::Result<Self> {
+        let mut writer = Self {
+            file,
+            map: None,
+            len: 0,
+            pos: 0,
+        };
+        writer.resize(cmp::max(size_hint, 4096))?;
+        Ok(writer)
+    }
+
+    fn resize(&mut self, len: u64) -> io::Result<()> {
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+
+        self.file.set_len(len)?;
+        self.len = len;
+        // The file is ours, opened with create_new, so nothing else should
+        // be truncating it underneath the mapping.
+        self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        Ok(())
+    }
+
+    /// Flush the mapping and truncate the file to the bytes written.
+    pub fn finish(mut self) -> io::Result<File> {
+        if let Some(map) = self.map.take() {
+            map.flush()?;
+        }
+
+        self.file.set_len(self.pos)?;
+        Ok(self.file)
+    }
+}
+
+impl Write for MmapWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.pos + buf.len() as u64;
+        if end > self.len {
+            let len = cmp::max(end, self.len + self.len / 2);
+            self.resize(len)?;
+        }
+
+        let map = self.map.as_mut().expect("MmapWriter lost its mapping");
+        map[self.pos as usize..end as usize].copy_from_slice(buf);
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.map {
+            Some(ref map) => map.flush_async(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! A simple membership service over TCP or Unix sockets.
+//!
+//! Each request is a kind byte, a big-endian u32 payload length, and the
+//! payload itself:
+//!
+//! * kind 0: an 8-byte big-endian u64 hash, looked up as-is
+//! * kind 1: a raw key, hashed with the server's --hash settings
+//!
+//! Every request gets a single byte in reply: 1 if found, 0 if not, or 255
+//! if the request was malformed or the key couldn't be hashed.  Requests may
+//! be pipelined, and each connection is served by its own thread and reader.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+
+use KeySpec;
+
+pub const REQUEST_HASH: u8 = 0;
+pub const REQUEST_KEY: u8 = 1;
+
+pub const REPLY_ABSENT: u8 = 0;
+pub const REPLY_PRESENT: u8 = 1;
+pub const REPLY_ERROR: u8 = 255;
+
+// Longer payloads can't be skipped safely, so the connection is dropped.
+const MAX_PAYLOAD: u32 = 1024 * 1024;
+
+fn serve_client<S: Read + Write>(stream: S, filename: &Path, key: &KeySpec) -> io::Result<()> {
+    let mut searcher = ::open_gcs(filename)?;
+    let key = &key.for_database(searcher.digest_order())?;
+    let mut stream = BufReader::new(stream);
+
+    loop {
+        let kind = match stream.read_u8() {
+            Ok(kind) => kind,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let len = stream.read_u32::<BigEndian>()?;
+        if len > MAX_PAYLOAD {
+            stream.get_mut().write_all(&[REPLY_ERROR])?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request payload too large",
+            ));
+        }
+
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload)?;
+
+        let target = match kind {
+            REQUEST_HASH if payload.len() == 8 => Some(BigEndian::read_u64(&payload)),
+            REQUEST_KEY => key.digest(&payload).ok(),
+            _ => None,
+        };
+
+        let reply = match target {
+            Some(target) if searcher.exists(target)? => REPLY_PRESENT,
+            Some(_) => REPLY_ABSENT,
+            None => REPLY_ERROR,
+        };
+
+        stream.get_mut().write_all(&[reply])?;
+    }
+}
+
+fn spawn_client<S>(stream: S, filename: &Arc<PathBuf>, key: &Arc<KeySpec>)
+where
+    S: Read + Write + Send + 'static,
+{
+    let filename = Arc::clone(filename);
+    let key = Arc::clone(key);
+
+    thread::spawn(move || {
+        if let Err(e) = serve_client(stream, &filename, &key) {
+            eprintln!("Client error: {}", e);
+        }
+    });
+}
+
+pub fn serve_tcp<P: AsRef<Path>>(filename: P, key: KeySpec, addr: &str) -> io::Result<()> {
+    // Fail early on a bad database or --endian rather than on the first
+    // connection.
+    key.for_database(::open_gcs(&filename)?.digest_order())?;
+
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+
+    let filename = Arc::new(filename.as_ref().to_path_buf());
+    let key = Arc::new(key);
+
+    // A failed accept only loses that client, so keep listening.
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => spawn_client(stream, &filename, &key),
+            Err(e) => eprintln!("accept: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn serve_unix<P: AsRef<Path>>(filename: P, key: KeySpec, path: &Path) -> io::Result<()> {
+    use std::os::unix::net::UnixListener;
+
+    key.for_database(::open_gcs(&filename)?.digest_order())?;
+
+    let listener = UnixListener::bind(path)?;
+    eprintln!("Listening on {}", path.display());
+
+    let filename = Arc::new(filename.as_ref().to_path_buf());
+    let key = Arc::new(key);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => spawn_client(stream, &filename, &key),
+            Err(e) => eprintln!("accept: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve_unix<P: AsRef<Path>>(_filename: P, _key: KeySpec, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Unix sockets are not supported on this platform",
+    ))
+}
@@ -1,22 +1,116 @@
-use std::time::Instant;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Where `Status` reports progress, so embedders can route it into their own
+/// logging or UI instead of the terminal.
+pub trait ProgressSink {
+    fn on_stage(&mut self, _name: &str) {}
+
+    /// A line of information along the way, such as the final sizes.
+    fn on_message(&mut self, _message: &str) {}
+
+    /// Something the caller may want to act on, such as a poor choice of
+    /// settings.
+    fn on_warning(&mut self, _message: &str) {}
+
+    /// `done` of `total` work items in the current stage, `elapsed` into it.
+    fn on_progress(&mut self, name: &str, done: u64, total: u64, elapsed: Duration);
+
+    fn on_stage_done(&mut self, name: &str, elapsed: Duration);
+
+    fn on_done(&mut self, elapsed: Duration);
+}
+
+/// The tool's own progress output, printed as it goes.
+pub struct PrintProgress {
+    to_stderr: bool,
+}
+
+impl PrintProgress {
+    pub fn stdout() -> Self {
+        Self { to_stderr: false }
+    }
+
+    /// For when stdout carries the output itself.
+    pub fn stderr() -> Self {
+        Self { to_stderr: true }
+    }
+
+    fn print(&self, line: fmt::Arguments) {
+        if self.to_stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+impl ProgressSink for PrintProgress {
+    fn on_message(&mut self, message: &str) {
+        self.print(format_args!("{}", message));
+    }
+
+    // Always stderr, so warnings aren't lost among the progress.
+    fn on_warning(&mut self, message: &str) {
+        eprintln!("Warning: {}", message);
+    }
+
+    fn on_progress(&mut self, name: &str, done: u64, total: u64, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let rate = if secs > 0.0 { done as f64 / secs } else { 0.0 };
+        self.print(format_args!(
+            "{}: {} of {}, {:.1}%, {:.0}/sec",
+            name,
+            done,
+            total,
+            (done as f64 / total as f64) * 100.0,
+            rate
+        ));
+    }
+
+    fn on_stage_done(&mut self, name: &str, elapsed: Duration) {
+        self.print(format_args!(
+            "{} complete in {:.2}s",
+            name,
+            (elapsed.as_secs() as f64) + (f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64)
+        ));
+    }
+
+    fn on_done(&mut self, elapsed: Duration) {
+        self.print(format_args!(
+            "Complete in {:.2}s",
+            (elapsed.as_secs() as f64) + (f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64)
+        ));
+    }
+}
 
-#[derive(Default)]
 pub struct Status {
     verbosity: u32,
+    sink: Box<dyn ProgressSink>,
     stage_name: Option<String>,
     work_count: u64,
     done_count: u64,
     step: u64,
-    start: Option<Instant>,
+    start: Instant,
     stage_start: Option<Instant>,
 }
 
 impl Status {
     pub fn new(verbosity: u32) -> Self {
+        Self::with_sink(verbosity, Box::new(PrintProgress::stdout()))
+    }
+
+    /// Report progress to `sink` rather than printing it.
+    pub fn with_sink(verbosity: u32, sink: Box<dyn ProgressSink>) -> Self {
         Self {
             verbosity,
-            start: Some(Instant::now()),
-            ..Self::default()
+            sink,
+            stage_name: None,
+            work_count: 0,
+            done_count: 0,
+            step: 0,
+            start: Instant::now(),
+            stage_start: None,
         }
     }
 
@@ -26,11 +120,28 @@ impl Status {
         self.stage_name = Some(name.to_owned());
         self.stage_start = Some(Instant::now());
         self.done_count = 0;
+        self.sink.on_stage(name);
+    }
+
+    /// Pass on a line of information, whatever the stage.
+    pub fn message(&mut self, message: &str) {
+        self.sink.on_message(message);
+    }
+
+    pub fn warning(&mut self, message: &str) {
+        self.sink.on_warning(message);
     }
 
     pub fn set_work(&mut self, count: u64) {
         self.work_count = count;
-        self.step = count / 20; // XXX clamp this
+        self.step = std::cmp::max(count / 20, 1);
+    }
+
+    /// Revise the current stage's total upwards, e.g. as each further input
+    /// file is estimated, so the percentage tracks the running total.
+    pub fn add_expected_work(&mut self, count: u64) {
+        let total = self.work_count + count;
+        self.set_work(total);
     }
 
     pub fn stage_work(&mut self, name: &str, work: u64) {
@@ -39,15 +150,13 @@ impl Status {
     }
 
     fn print_status(&mut self) {
-        let elapsed = self.start.unwrap().elapsed();
-        println!(
-            "{}: {} of {}, {:.1}%, {:.0}/sec",
+        // done_count restarts with each stage, so time it from there.
+        let elapsed = self.stage_start.unwrap_or(self.start).elapsed();
+        self.sink.on_progress(
             self.stage_name.as_ref().unwrap(),
             self.done_count,
             self.work_count,
-            (self.done_count as f64 / self.work_count as f64) * 100.0,
-            (self.done_count as f64) / (elapsed.as_secs() as f64)
-                + (f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64)
+            elapsed,
         );
     }
 
@@ -55,7 +164,7 @@ impl Status {
     pub fn set_work_done(&mut self, count: u64) {
         self.done_count = count;
 
-        if self.done_count % self.step == 0 {
+        if self.step > 0 && self.done_count % self.step == 0 {
             self.print_status();
         }
     }
@@ -63,7 +172,7 @@ impl Status {
     pub fn add_work(&mut self, count: u64) {
         self.done_count += count;
 
-        if self.done_count % self.step == 0 {
+        if self.step > 0 && self.done_count % self.step == 0 {
             self.print_status();
         }
     }
@@ -75,22 +184,13 @@ impl Status {
     pub fn finish_stage(&mut self) {
         if let Some(ref stage) = self.stage_name {
             let elapsed = self.stage_start.unwrap().elapsed();
-            println!(
-                "{} complete in {:.2}s",
-                stage,
-                (elapsed.as_secs() as f64)
-                    + (f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64)
-            );
+            self.sink.on_stage_done(stage, elapsed);
         }
         self.stage_name = None;
     }
 
     pub fn done(mut self) {
         self.finish_stage();
-        let elapsed = self.start.unwrap().elapsed();
-        println!(
-            "Complete in {:.2}s",
-            (elapsed.as_secs() as f64) + (f64::from(elapsed.subsec_nanos()) / 1_000_000_000_f64)
-        );
+        self.sink.on_done(self.start.elapsed());
     }
 }
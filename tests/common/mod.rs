@@ -0,0 +1,94 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub fn gcstool() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_gcstool"))
+}
+
+pub fn scratch_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("gcstool-{}-{}", std::process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Build `name`.gcs in the scratch directory from the keys in `input`,
+/// hashed with `hash` and passing `args` on to create.
+pub fn build(hash: &str, name: &str, input: &str, args: &[&str]) -> PathBuf {
+    let in_path = scratch_path(&format!("{}.txt", name));
+    let out = scratch_path(&format!("{}.gcs", name));
+    fs::write(&in_path, input).unwrap();
+
+    let output = gcstool()
+        .args(["-H", hash, "create"])
+        .args(args)
+        .arg(&in_path)
+        .arg(&out)
+        .output()
+        .expect("failed to run gcstool");
+    let _ = fs::remove_file(&in_path);
+    assert!(
+        output.status.success(),
+        "create failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    out
+}
+
+/// Look up each line of `keys` in `db`, returning query's answer for each.
+pub fn query(hash: &str, db: &Path, keys: &str) -> Vec<String> {
+    query_with(hash, db, keys, &[])
+}
+
+/// Like `query`, passing `args` on to query.
+pub fn query_with(hash: &str, db: &Path, keys: &str, args: &[&str]) -> Vec<String> {
+    let mut child = gcstool()
+        .args(["-H", hash, "query", "--batch"])
+        .args(args)
+        .arg(db)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run gcstool");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(keys.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
+
+/// A key for the hex hash whose value is `value` itself.
+pub fn hex_key(value: u64) -> String {
+    format!("{:016x}", value)
+}
+
+/// Every value `db` holds, in order, as range lists them.
+pub fn range(db: &Path) -> Vec<u64> {
+    let output = gcstool()
+        .arg("range")
+        .arg(db)
+        .output()
+        .expect("failed to run gcstool");
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| l.parse().unwrap())
+        .collect()
+}
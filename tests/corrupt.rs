@@ -0,0 +1,58 @@
+//! A damaged footer must be refused when the database is opened, not
+//! trusted until the first query trips over it.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+
+use common::{build, gcstool};
+
+/// Overwrite the footer field at `offset` (N at 0, P at 8, end of data at
+/// 16, index length at 24) with `value`, big-endian as create writes it.
+fn patch_footer(db: &Path, offset: usize, value: u64) {
+    let mut bytes = fs::read(db).unwrap();
+    let at = bytes.len() - 40 + offset;
+    bytes[at..at + 8].copy_from_slice(&value.to_be_bytes());
+    fs::write(db, bytes).unwrap();
+}
+
+fn query_error(db: &Path) -> String {
+    let output = gcstool()
+        .args(["-H", "sha1", "query", "--batch"])
+        .arg(db)
+        .output()
+        .expect("failed to run gcstool");
+    assert!(!output.status.success(), "corrupt database opened");
+
+    String::from_utf8(output.stderr).unwrap()
+}
+
+fn corrupted(name: &str, offset: usize, value: u64) -> String {
+    let db = build("sha1", name, "a\nb\nc\n", &["-p", "1024"]);
+    patch_footer(&db, offset, value);
+    let error = query_error(&db);
+    let _ = fs::remove_file(&db);
+
+    error
+}
+
+#[test]
+fn index_longer_than_the_file_is_rejected() {
+    assert!(corrupted("long-index", 24, 1 << 40).contains("Corrupt index"));
+}
+
+#[test]
+fn index_past_the_footer_is_rejected() {
+    assert!(corrupted("late-index", 16, 1 << 40).contains("Corrupt index"));
+}
+
+#[test]
+fn zero_p_is_rejected() {
+    assert!(corrupted("zero-p", 8, 0).contains("Corrupt footer"));
+}
+
+#[test]
+fn overflowing_n_times_p_is_rejected() {
+    assert!(corrupted("huge-n", 0, u64::MAX / 2).contains("Corrupt footer"));
+}
@@ -0,0 +1,149 @@
+//! How create reads its input.
+
+mod common;
+
+use std::fs;
+
+use common::{build, gcstool, query, scratch_path};
+
+#[test]
+fn final_line_without_newline_is_kept() {
+    let db = build("sha1", "no-newline", "first\nsecond\nlast", &["-p", "1024"]);
+
+    let results = query("sha1", &db, "first\nsecond\nlast\n");
+    let _ = fs::remove_file(&db);
+    assert_eq!(results, vec!["Found"; 3]);
+}
+
+#[test]
+fn empty_input_builds_an_empty_database() {
+    let db = build("sha1", "empty", "", &["-p", "16"]);
+
+    let results = query("sha1", &db, "a\nb\n");
+    let _ = fs::remove_file(&db);
+    assert_eq!(results, vec!["Not found"; 2]);
+}
+
+#[test]
+fn length_limits_skip_records() {
+    let db = build(
+        "sha1",
+        "length-limits",
+        "a\nabcdef\n\nabcdefghijk\n",
+        &["-p", "1024", "--min-length", "1", "--max-length", "6"],
+    );
+
+    let results = query("sha1", &db, "a\nabcdef\n\nabcdefghijk\n");
+    let _ = fs::remove_file(&db);
+    assert_eq!(results, vec!["Found", "Found", "Not found", "Not found"]);
+}
+
+#[test]
+fn limit_zero_is_rejected() {
+    let out = scratch_path("limit-zero.gcs");
+
+    let output = gcstool()
+        .args(["create", "--limit", "0", "tests/data/golden.txt"])
+        .arg(&out)
+        .output()
+        .expect("failed to run gcstool");
+    assert!(!output.status.success());
+    assert!(!out.exists());
+}
+
+#[test]
+fn oversized_index_warns_on_stderr() {
+    let input = scratch_path("big-index.txt");
+    let out = scratch_path("big-index.gcs");
+    let keys: String = (0..1000).map(|i| format!("key {}\n", i)).collect();
+    fs::write(&input, keys).unwrap();
+
+    let output = gcstool()
+        .args(["create", "-p", "2", "-i", "1"])
+        .arg(&input)
+        .arg(&out)
+        .output()
+        .expect("failed to run gcstool");
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&out);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("Warning: index is larger than the data it indexes"));
+}
+
+#[test]
+fn failed_create_removes_output() {
+    let input = scratch_path("max-skip.txt");
+    let out = scratch_path("max-skip.gcs");
+    fs::write(&input, "0123456789abcdef\nnot hex\n").unwrap();
+
+    for _ in 0..2 {
+        let output = gcstool()
+            .args(["-H", "hex", "create", "--max-skip", "10"])
+            .arg(&input)
+            .arg(&out)
+            .output()
+            .expect("failed to run gcstool");
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("Skipped"),
+            "should fail on --max-skip every time, not on the leftover output"
+        );
+        assert!(!out.exists());
+    }
+    let _ = fs::remove_file(&input);
+}
+
+#[test]
+fn few_items_suggest_no_index() {
+    let input = scratch_path("few-items.txt");
+    let out = scratch_path("few-items.gcs");
+    fs::write(&input, "a\nb\nc\n").unwrap();
+
+    let output = gcstool()
+        .arg("create")
+        .arg(&input)
+        .arg(&out)
+        .output()
+        .expect("failed to run gcstool");
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&out);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let suggestion = stdout
+        .lines()
+        .find(|l| l.starts_with("Suggested index granularity"))
+        .expect("no suggestion");
+    assert!(suggestion.ends_with("none, 3 items are few enough to scan"));
+}
+
+#[test]
+fn query_bits_sets_the_mean_query_cost() {
+    let input: String = (0..20_000).map(|i| format!("key {}\n", i)).collect();
+    let db = build(
+        "sha1",
+        "query-bits",
+        &input,
+        &["-p", "1024", "--query-bits", "500"],
+    );
+
+    let output = gcstool()
+        .args(["bench", "-k", "5000"])
+        .arg(&db)
+        .output()
+        .expect("failed to run gcstool");
+    let _ = fs::remove_file(&db);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let bits: f64 = stdout
+        .lines()
+        .find(|l| l.starts_with("Query cost:"))
+        .and_then(|l| l.rsplit(", ").next())
+        .and_then(|v| v.strip_suffix(" bits"))
+        .expect("no query cost")
+        .parse()
+        .unwrap();
+    assert!((bits - 500.0).abs() < 75.0, "queries decoded {} bits", bits);
+}
@@ -0,0 +1,95 @@
+//! Create records the digest byte order, so queries answer the same way
+//! without being told it again.
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+
+use common::{gcstool, query, scratch_path};
+
+const INPUT: &str = "tests/data/golden.txt";
+
+fn create_le(name: &str) -> PathBuf {
+    let out = scratch_path(name);
+    let status = gcstool()
+        .args(["-H", "sha1", "-E", "le", "create", "-p", "1024", INPUT])
+        .arg(&out)
+        .stderr(Stdio::null())
+        .status()
+        .expect("failed to run gcstool");
+    assert!(status.success());
+
+    out
+}
+
+fn query_as(endian: &str, db: &Path, keys: &str) -> Output {
+    let mut child = gcstool()
+        .args(["-H", "sha1", "-E", endian, "query", "--batch"])
+        .arg(db)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run gcstool");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(keys.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn queries_follow_the_recorded_digest_order() {
+    let keys = fs::read_to_string(INPUT).unwrap();
+    let db = create_le("endian-le.gcs");
+
+    let found = query("sha1", &db, &keys);
+    let agreed = query_as("le", &db, &keys);
+    let disagreed = query_as("be", &db, &keys);
+    let _ = fs::remove_file(&db);
+
+    assert!(!found.is_empty());
+    assert!(found.iter().all(|answer| answer == "Found"));
+
+    assert!(agreed.status.success());
+    assert_eq!(
+        String::from_utf8(agreed.stdout)
+            .unwrap()
+            .lines()
+            .collect::<Vec<_>>(),
+        found
+    );
+
+    assert!(!disagreed.status.success());
+    assert!(String::from_utf8(disagreed.stderr)
+        .unwrap()
+        .contains("--endian le"));
+}
+
+#[test]
+fn reindex_keeps_the_digest_order() {
+    let keys = fs::read_to_string(INPUT).unwrap();
+    let db = create_le("endian-reindex-in.gcs");
+    let reindexed = scratch_path("endian-reindex-out.gcs");
+
+    let status = gcstool()
+        .args(["reindex", "-i", "8"])
+        .arg(&db)
+        .arg(&reindexed)
+        .stdout(Stdio::null())
+        .status()
+        .expect("failed to run gcstool");
+    assert!(status.success());
+
+    let found = query("sha1", &reindexed, &keys);
+    for path in &[db, reindexed] {
+        let _ = fs::remove_file(path);
+    }
+
+    assert!(found.iter().all(|answer| answer == "Found"));
+}
@@ -0,0 +1,182 @@
+//! How create estimates its input before building.
+
+mod common;
+
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use common::{build, gcstool, scratch_path};
+
+/// The number after `prefix` in some line of `out`.
+fn field(out: &str, prefix: &str) -> u64 {
+    out.lines()
+        .find_map(|l| l.strip_prefix(prefix))
+        .and_then(|v| v.split(' ').next())
+        .unwrap_or_else(|| panic!("no {:?} line in {:?}", prefix, out))
+        .parse()
+        .unwrap()
+}
+
+/// What create --dry-run prints for `input` with `args`.
+fn dry_run(input: &Path, args: &[&str]) -> String {
+    let output = gcstool()
+        .args(["create", "--dry-run"])
+        .args(args)
+        .arg(input)
+        .arg(scratch_path("unused.gcs"))
+        .output()
+        .expect("failed to run gcstool");
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The item count create --dry-run estimates for a file holding `input`.
+fn estimated_items(name: &str, input: &[u8]) -> u64 {
+    let path = scratch_path(name);
+    fs::write(&path, input).unwrap();
+
+    let out = dry_run(&path, &[]);
+    let _ = fs::remove_file(&path);
+    field(&out, "Estimated items: ")
+}
+
+#[test]
+fn small_files_are_counted_exactly() {
+    assert_eq!(estimated_items("empty.txt", b""), 0);
+    assert_eq!(estimated_items("one-line.txt", b"only\n"), 1);
+    assert_eq!(estimated_items("unterminated.txt", b"a\nb\nc"), 3);
+
+    let long_line = vec![b'x'; 1024 * 1024];
+    assert_eq!(estimated_items("long-line.txt", &long_line), 1);
+}
+
+#[test]
+fn skewed_line_lengths_are_sampled_across_the_file() {
+    // Well over the 16MB that's counted exactly: a quarter short lines, then
+    // long ones, as in sorted input.
+    let path = scratch_path("skewed.txt");
+    let mut lines = 0;
+    {
+        let mut out = BufWriter::new(fs::File::create(&path).unwrap());
+        for i in 0..1_000_000 {
+            writeln!(out, "{:08}", i).unwrap();
+            lines += 1;
+        }
+        for i in 0..300_000 {
+            writeln!(out, "{:0100}", i).unwrap();
+            lines += 1;
+        }
+    }
+
+    let spread = field(&dry_run(&path, &[]), "Estimated items: ");
+    let start_only = field(
+        &dry_run(&path, &["--sample-chunks", "1"]),
+        "Estimated items: ",
+    );
+    let _ = fs::remove_file(&path);
+
+    let error = |estimate: u64| (estimate as f64 - lines as f64).abs() / lines as f64;
+    assert!(
+        error(spread) < 0.1,
+        "estimated {} of {} lines",
+        spread,
+        lines
+    );
+    assert!(error(spread) < error(start_only));
+}
+
+#[test]
+fn progress_outgrows_an_underestimate() {
+    // Long lines first, so sampling only the start counts too few.
+    let input = scratch_path("underestimate.txt");
+    let out = scratch_path("underestimate.gcs");
+    {
+        let mut file = BufWriter::new(fs::File::create(&input).unwrap());
+        for i in 0..100_000 {
+            writeln!(file, "{:0100}", i).unwrap();
+        }
+        for i in 0..1_000_000 {
+            writeln!(file, "{:08}", i).unwrap();
+        }
+    }
+
+    let output = gcstool()
+        .args(["create", "--sample-chunks", "1"])
+        .arg(&input)
+        .arg(&out)
+        .output()
+        .expect("failed to run gcstool");
+    let _ = fs::remove_file(&input);
+    let _ = fs::remove_file(&out);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let progress: Vec<(u64, u64)> = stdout
+        .lines()
+        .filter_map(|l| l.strip_prefix("Hashing: "))
+        .filter_map(|l| {
+            let mut words = l.split(&[' ', ','][..]);
+            let done = words.next()?.parse().ok()?;
+            let total = words.nth(1)?.parse().ok()?;
+            Some((done, total))
+        })
+        .collect();
+    assert!(progress[0].1 < 1_100_000, "the estimate wasn't short");
+    for &(done, total) in &progress {
+        assert!(done <= total, "{} of {} hashed", done, total);
+    }
+}
+
+#[test]
+fn size_estimates_match_built_databases() {
+    let n = 20_000;
+    let input: String = (0..n).map(|i| format!("key {}\n", i)).collect();
+    let in_path = scratch_path("sizes-input.txt");
+    fs::write(&in_path, &input).unwrap();
+
+    for &(p, i) in &[(16, 16), (1024, 1024), (1 << 20, 64)] {
+        let args = ["-p", &p.to_string(), "-i", &i.to_string()];
+        let estimate = dry_run(&in_path, &args);
+
+        let db = build("sha1", "sizes", &input, &args);
+        let output = gcstool().arg("info").arg(&db).output().unwrap();
+        let _ = fs::remove_file(&db);
+        let info = String::from_utf8(output.stdout).unwrap();
+
+        let (data, estimated_data) = (
+            field(&info, "Data: "),
+            field(&estimate, "Estimated data size: "),
+        );
+        // Values colliding mod n*p, about 1 in 2p of them, are only encoded
+        // and indexed once, which the estimate can't know.
+        let collisions = 1.0 / p as f64;
+        let tolerance = 0.02 + collisions;
+        assert!(
+            (data as f64 - estimated_data as f64).abs() / (data as f64) < tolerance,
+            "p={} i={}: estimated {} bytes of data, built {}",
+            p,
+            i,
+            estimated_data,
+            data
+        );
+
+        // Builders put the first index point after the first i members, so
+        // there's at most one fewer than n/i besides.
+        let (index, estimated_index) = (
+            field(&info, "Index: ") * 16,
+            field(&estimate, "Estimated index size: "),
+        );
+        assert!(
+            index <= estimated_index
+                && (estimated_index - index) as f64 <= 16.0 + estimated_index as f64 * collisions,
+            "p={} i={}: estimated {} bytes of index, built {}",
+            p,
+            i,
+            estimated_index,
+            index
+        );
+    }
+    let _ = fs::remove_file(&in_path);
+}
@@ -0,0 +1,65 @@
+//! The v0 format is stable: a fixed input built with fixed settings must
+//! come out byte for byte the same as the committed golden file, and that
+//! file must stay readable.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+
+use common::{build, gcstool, query, query_with};
+
+const GOLDEN_INPUT: &str = "tests/data/golden.txt";
+const GOLDEN_GCS: &str = "tests/data/golden.gcs";
+
+#[test]
+fn create_matches_golden_file() {
+    let input = fs::read_to_string(GOLDEN_INPUT).unwrap();
+    let out = build("sha1", "golden", &input, &["-p", "1024", "-i", "16"]);
+
+    let built = fs::read(&out).unwrap();
+    let _ = fs::remove_file(&out);
+    let golden = fs::read(GOLDEN_GCS).unwrap();
+
+    assert!(
+        built == golden,
+        "encoding has drifted from {}, which breaks existing databases",
+        GOLDEN_GCS
+    );
+}
+
+#[test]
+fn create_to_stdout_matches_golden_file() {
+    let output = gcstool()
+        .args(["-H", "sha1", "create"])
+        .args(["-p", "1024", "-i", "16", GOLDEN_INPUT, "-"])
+        .stderr(Stdio::null())
+        .output()
+        .expect("failed to run gcstool");
+    assert!(output.status.success());
+
+    assert!(
+        output.stdout == fs::read(GOLDEN_GCS).unwrap(),
+        "writing to stdout differs from writing to a file"
+    );
+}
+
+#[test]
+fn golden_file_finds_every_key() {
+    let input = fs::read_to_string(GOLDEN_INPUT).unwrap();
+    let keys = input.lines().filter(|k| !k.is_empty()).count();
+
+    let results = query("sha1", Path::new(GOLDEN_GCS), &input);
+    let found = results.iter().filter(|l| *l == "Found").count();
+    assert_eq!(found, keys);
+}
+
+#[test]
+fn golden_file_finds_every_key_in_memory() {
+    let input = fs::read_to_string(GOLDEN_INPUT).unwrap();
+
+    let on_disk = query("sha1", Path::new(GOLDEN_GCS), &input);
+    let in_memory = query_with("sha1", Path::new(GOLDEN_GCS), &input, &["--in-memory"]);
+    assert_eq!(in_memory, on_disk);
+}
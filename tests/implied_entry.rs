@@ -0,0 +1,129 @@
+//! Readers start any search below the first stored index point from an
+//! implied entry of value 0 at the first bit of the data.  That's only right
+//! while builders encode the first member as a delta from 0, so pin it down
+//! around the edges: below the smallest member, just below the first index
+//! point, and with 0 itself a member, including at small p where a lone
+//! terminator is padded out to the size of two codes.  With a granularity
+//! above the member count it's the only entry there is.
+//!
+//! Hex keys are their own hashes, and every value here is below n*p, so the
+//! members are exactly the values we put in.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{gcstool, hex_key, range};
+
+const P: u64 = 1024;
+const GRANULARITY: usize = 8;
+
+fn build(name: &str, members: &[u64]) -> PathBuf {
+    build_with_p(name, members, P)
+}
+
+fn build_with_p(name: &str, members: &[u64], p: u64) -> PathBuf {
+    build_with(name, members, p, GRANULARITY as u64)
+}
+
+fn build_with(name: &str, members: &[u64], p: u64, granularity: u64) -> PathBuf {
+    let keys: String = members.iter().map(|&v| hex_key(v) + "\n").collect();
+    common::build(
+        "hex",
+        name,
+        &keys,
+        &["-p", &p.to_string(), "-i", &granularity.to_string()],
+    )
+}
+
+fn query(db: &Path, values: &[u64]) -> Vec<bool> {
+    let keys: String = values.iter().map(|&v| hex_key(v) + "\n").collect();
+    common::query("hex", db, &keys)
+        .iter()
+        .map(|l| l == "Found")
+        .collect()
+}
+
+/// Spaced out so there's room below each member, with several index points.
+fn members(first: u64) -> Vec<u64> {
+    (0..30).map(|i| first + i * 500).collect()
+}
+
+#[test]
+fn below_smallest_member_is_absent() {
+    let members = members(500);
+    let db = build("below-smallest", &members);
+
+    assert_eq!(query(&db, &[0, 1, 250, 499]), vec![false; 4]);
+    assert_eq!(query(&db, &members), vec![true; members.len()]);
+    assert_eq!(range(&db), members);
+
+    let _ = fs::remove_file(&db);
+}
+
+#[test]
+fn just_below_first_index_point_is_absent() {
+    let members = members(500);
+    let db = build("below-index", &members);
+
+    let first_point = members[GRANULARITY];
+    assert_eq!(
+        query(&db, &[first_point - 1, members[GRANULARITY - 1] + 1]),
+        vec![false, false]
+    );
+    assert_eq!(
+        query(&db, &[members[GRANULARITY - 1], first_point]),
+        vec![true, true]
+    );
+
+    let _ = fs::remove_file(&db);
+}
+
+#[test]
+fn zero_is_a_member() {
+    let members = members(0);
+    let db = build("zero-member", &members);
+
+    assert_eq!(query(&db, &members), vec![true; members.len()]);
+    assert_eq!(query(&db, &[1, 499]), vec![false, false]);
+    assert_eq!(range(&db), members);
+
+    let _ = fs::remove_file(&db);
+}
+
+#[test]
+fn small_p_tells_zero_from_an_empty_set() {
+    for &p in &[4, 8] {
+        let empty = build_with_p("small-p-empty", &[], p);
+        assert_eq!(range(&empty), Vec::<u64>::new());
+        assert_eq!(query(&empty, &[0]), vec![false]);
+        let _ = fs::remove_file(&empty);
+
+        let zero = build_with_p("small-p-zero", &[0], p);
+        assert_eq!(range(&zero), vec![0]);
+        assert_eq!(query(&zero, &[0, 1]), vec![true, false]);
+        let _ = fs::remove_file(&zero);
+    }
+}
+
+#[test]
+fn granularity_above_count_scans_from_the_start() {
+    let members = members(500)[..10].to_vec();
+    let db = build_with("no-index", &members, P, 1_000_000);
+
+    let output = gcstool()
+        .arg("dump-index")
+        .arg(&db)
+        .output()
+        .expect("failed to run gcstool");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty(), "expected no index entries");
+
+    assert_eq!(query(&db, &members), vec![true; members.len()]);
+    let gaps: Vec<u64> = members.iter().map(|v| v - 1).collect();
+    assert_eq!(query(&db, &gaps), vec![false; gaps.len()]);
+    assert_eq!(range(&db), members);
+
+    let _ = fs::remove_file(&db);
+}
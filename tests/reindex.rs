@@ -0,0 +1,47 @@
+//! Reindexing should give exactly the database a fresh build would have.
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use common::{build, gcstool, scratch_path};
+
+const INPUT: &str = "tests/data/golden.txt";
+
+fn create(name: &str, index_granularity: &str) -> PathBuf {
+    let input = fs::read_to_string(INPUT).unwrap();
+    build(
+        "sha1",
+        name,
+        &input,
+        &["-p", "1024", "-i", index_granularity],
+    )
+}
+
+#[test]
+fn reindex_matches_fresh_build() {
+    let coarse = create("reindex-coarse", "1024");
+    let fine = create("reindex-fine", "8");
+    let reindexed = scratch_path("reindex-reindexed.gcs");
+
+    let status = gcstool()
+        .args(["reindex", "-i", "8"])
+        .arg(&coarse)
+        .arg(&reindexed)
+        .stdout(Stdio::null())
+        .status()
+        .expect("failed to run gcstool");
+    assert!(status.success());
+
+    let expected = fs::read(&fine).unwrap();
+    let got = fs::read(&reindexed).unwrap();
+    for path in &[coarse, fine, reindexed] {
+        let _ = fs::remove_file(path);
+    }
+    assert!(
+        got == expected,
+        "reindexed database differs from a fresh build"
+    );
+}
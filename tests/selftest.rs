@@ -0,0 +1,21 @@
+//! The hidden selftest builds a random database and checks every way of
+//! querying it against the members it put in, including whether each was
+//! matched by an index point or found by scanning.
+
+mod common;
+
+use common::gcstool;
+
+#[test]
+fn selftest_passes() {
+    for p in ["4", "1024"] {
+        let output = gcstool()
+            .args(["selftest", "-n", "20000", "-p", p])
+            .output()
+            .expect("failed to run gcstool");
+        let stdout = String::from_utf8(output.stdout).unwrap();
+
+        assert!(output.status.success(), "p={}: {}", p, stdout);
+        assert!(stdout.contains("Hit sources reported correctly: 20000 of 20000"));
+    }
+}
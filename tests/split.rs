@@ -0,0 +1,87 @@
+//! Shards should hold exactly the original's members between them, each
+//! answering for its own slice of the values, without growing.
+//!
+//! Hex keys are their own hashes, and every value here is below n*p, so the
+//! members are exactly the values we put in.
+
+mod common;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use common::{build, gcstool, hex_key, query, range, scratch_path};
+
+const SHARDS: u64 = 4;
+const P: u64 = 64;
+
+/// The value of an `info` line starting with `prefix`, up to the next
+/// space or comma.
+fn info(db: &Path, prefix: &str) -> u64 {
+    let output = gcstool()
+        .arg("info")
+        .arg(db)
+        .output()
+        .expect("failed to run gcstool");
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .find_map(|l| l.strip_prefix(prefix))
+        .and_then(|v| v.split(&[' ', ','][..]).next())
+        .expect("no such info line")
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn shards_partition_the_members() {
+    // Gaps of 37 all code in 7 bits at p=64, and nothing lands in the last
+    // shard.
+    let members: Vec<u64> = (0..1000).map(|i| 5 + i * 37).collect();
+    let keys: String = members.iter().map(|&v| hex_key(v) + "\n").collect();
+    let db = build("hex", "split", &keys, &["-p", &P.to_string(), "-i", "64"]);
+
+    let prefix = scratch_path("split-shard");
+    let status = gcstool()
+        .args(["split", "--shards", &SHARDS.to_string()])
+        .arg(&db)
+        .arg(&prefix)
+        .output()
+        .expect("failed to run gcstool")
+        .status;
+    assert!(status.success());
+
+    let np = members.len() as u64 * P;
+    let shards: Vec<PathBuf> = (0..SHARDS)
+        .map(|k| PathBuf::from(format!("{}.{}.gcs", prefix.display(), k)))
+        .collect();
+
+    let mut all = Vec::new();
+    for (k, shard) in shards.iter().enumerate() {
+        let held = range(shard);
+        let expected: Vec<u64> = members
+            .iter()
+            .cloned()
+            .filter(|v| v * SHARDS / np == k as u64)
+            .collect();
+        assert_eq!(held, expected, "shard {} holds the wrong members", k);
+        all.extend(held);
+
+        let keys: String = expected.iter().map(|&v| hex_key(v) + "\n").collect();
+        assert!(query("hex", shard, &keys).iter().all(|l| l == "Found"));
+
+        // Every code here is 7 bits, the first one from the shard's base
+        // included, then the terminator and padding.
+        let count = expected.len() as u64;
+        assert_eq!(info(shard, "Items: "), count);
+        assert!(info(shard, "Data: ") * 8 <= (count + 1) * 7 + 7);
+    }
+    assert_eq!(all, members);
+    assert_eq!(range(&shards[SHARDS as usize - 1]), Vec::<u64>::new());
+
+    let _ = fs::remove_file(&db);
+    for shard in &shards {
+        let _ = fs::remove_file(shard);
+    }
+}
@@ -0,0 +1,58 @@
+//! A database built with --header carries N and P up front, so range can
+//! decode it from a pipe without seeking to the footer.
+
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+
+use common::{build, gcstool};
+
+fn range(db: &Path, from_stdin: bool) -> Result<String, String> {
+    let mut command = gcstool();
+    command.args(["range", "--from", "1000", "--to", "100000000"]);
+    if from_stdin {
+        command
+            .arg("-")
+            .stdin(Stdio::from(fs::File::open(db).unwrap()));
+    } else {
+        command.arg(db);
+    }
+    let output = command.output().expect("failed to run gcstool");
+
+    if output.status.success() {
+        Ok(String::from_utf8(output.stdout).unwrap())
+    } else {
+        Err(String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+#[test]
+fn header_databases_stream_from_stdin() {
+    let input: String = (0..5000).map(|i| format!("key {}\n", i)).collect();
+
+    for order in ["be", "le"] {
+        let db = build(
+            "sha1",
+            "stream",
+            &input,
+            &["-p", "1024", "--header", "--byte-order", order],
+        );
+        let listed = range(&db, false).unwrap();
+        let streamed = range(&db, true);
+        let _ = fs::remove_file(&db);
+
+        assert!(!listed.is_empty());
+        assert_eq!(streamed.unwrap(), listed, "byte order {}", order);
+    }
+}
+
+#[test]
+fn streaming_needs_a_header() {
+    let db = build("sha1", "no-header", "a\nb\n", &["-p", "1024"]);
+    let streamed = range(&db, true);
+    let _ = fs::remove_file(&db);
+
+    assert!(streamed.unwrap_err().contains("--header"));
+}